@@ -4,7 +4,8 @@ use clap::{Args, Parser, Subcommand};
 #[derive(Debug, Parser)]
 #[clap(
 	author = "Lucy <lucy@system76.com>",
-	about = "CLI tool for managing btrfs snapshots on Pop!_OS"
+	about = "CLI tool for managing btrfs snapshots on Pop!_OS",
+	after_help = "EXIT CODES:\n    1    failed\n    2    invalid usage\n    3    snapshot not found\n    4    busy with other operations\n    5    permission denied\n    6    not enough space\n    7    invalid subvolume\n    130  cancelled"
 )]
 pub struct CliArgs {
 	/// Whether to automatically confirm "yes" to prompts or not.
@@ -17,13 +18,34 @@ pub struct CliArgs {
 #[derive(Debug, Subcommand)]
 pub enum CliSubcommand {
 	/// List all snapshots.
-	List,
+	List(CliList),
 	/// Take a snapshot of the current system state.
 	Create(CliCreate),
 	/// Delete an existing snapshot
 	Delete(CliDelete),
 	/// Restore your system to a snapshot.
 	Restore(CliRestore),
+	/// Protect a snapshot from being deleted.
+	Protect(CliProtect),
+	/// Allow a protected snapshot to be deleted again.
+	Unprotect(CliProtect),
+	/// Manage deleted snapshots.
+	Trash(CliTrash),
+	/// Make writable copies of a snapshot's subvolumes, to experiment with.
+	Clone(CliClone),
+	/// Print changes to snapshots as they happen.
+	Watch,
+}
+
+#[derive(Debug, Args)]
+pub struct CliList {
+	/// Only list snapshots that have this tag.
+	/// Can be given multiple times, to only list snapshots that have all of them.
+	#[clap(short = 'T', long = "tag")]
+	pub tags: Vec<String>,
+	/// Show how snapshots descend from each other through restores.
+	#[clap(long)]
+	pub tree: bool,
 }
 
 #[derive(Debug, Args)]
@@ -34,10 +56,22 @@ pub struct CliCreate {
 	/// The description of the snapshot
 	#[clap(short, long)]
 	pub description: Option<String>,
-	/// Which subvolumes to snapshot.
+	/// Which subvolumes to snapshot, as glob patterns (such as `@*/var/cache/**`)
+	/// or the absolute paths they're mounted at (such as `/var/log`).
 	/// Defaults to everything except for @home.
 	#[clap(short, long)]
 	pub subvolumes: Option<Vec<String>>,
+	/// What triggered this snapshot.
+	/// One of "manual" (the default), "schedule", "apt" or "import".
+	#[clap(short, long)]
+	pub trigger: Option<String>,
+	/// A tag to label the snapshot with.
+	/// Can be given multiple times.
+	#[clap(short = 'T', long = "tag")]
+	pub tags: Vec<String>,
+	/// Only show which subvolumes would be snapshotted, without taking a snapshot.
+	#[clap(long)]
+	pub dry_run: bool,
 }
 
 #[derive(Debug, Args)]
@@ -45,6 +79,16 @@ pub struct CliDelete {
 	/// The UUID of the snapshot to delete.
 	#[clap(short, long)]
 	pub snapshot: String,
+	/// Delete the snapshot even if it's protected.
+	/// Requires root.
+	#[clap(short, long)]
+	pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CliProtect {
+	/// The UUID of the snapshot.
+	pub snapshot: String,
 }
 
 #[derive(Debug, Args)]
@@ -56,3 +100,37 @@ pub struct CliRestore {
 	/// The UUID of the snapshot to restore
 	pub snapshot: String,
 }
+
+#[derive(Debug, Args)]
+pub struct CliClone {
+	/// A name for the clone.
+	#[clap(short, long)]
+	pub name: Option<String>,
+	/// Delete the clone with this ID, instead of making a new one.
+	#[clap(short, long)]
+	pub delete: Option<String>,
+	/// The UUID of the snapshot to clone.
+	pub snapshot: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CliTrash {
+	#[clap(subcommand)]
+	pub subcommand: CliTrashSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliTrashSubcommand {
+	/// List deleted snapshots.
+	List,
+	/// Bring a deleted snapshot back.
+	Restore(CliTrashRestore),
+	/// Permanently delete every snapshot in the trash.
+	Purge,
+}
+
+#[derive(Debug, Args)]
+pub struct CliTrashRestore {
+	/// The UUID of the deleted snapshot.
+	pub snapshot: String,
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::args::CliClone;
+use color_eyre::{eyre::WrapErr, Result};
+use owo_colors::OwoColorize;
+use zbus::zvariant::OwnedObjectPath;
+use zbus_pop_snapshot::{Error, PopSnapshotProxy, SnapshotProxy};
+
+pub async fn clone(clone: &CliClone) -> Result<()> {
+	let connection = zbus::Connection::system()
+		.await
+		.wrap_err("failed to connect to D-Bus system bus")?;
+	let proxy = PopSnapshotProxy::new(&connection)
+		.await
+		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
+	let snapshot_path = Option::<OwnedObjectPath>::from(
+		proxy
+			.find_snapshot(&clone.snapshot)
+			.await
+			.wrap_err("failed to list snapshots")?,
+	)
+	.ok_or_else(|| Error::NotFound(format!("snapshot {} not found", clone.snapshot)))?;
+
+	let snapshot = SnapshotProxy::builder(&connection)
+		.path(&snapshot_path)
+		.wrap_err_with(|| format!("failed to connect to snapshot {}", snapshot_path.as_str()))?
+		.build()
+		.await
+		.wrap_err_with(|| format!("failed to connect to snapshot {}", snapshot_path.as_str()))?;
+
+	if let Some(id) = &clone.delete {
+		snapshot
+			.delete_clone(id)
+			.await
+			.wrap_err_with(|| format!("failed to delete clone {}", id))?;
+		println!("{} clone {}", "Deleted".red(), id.cyan());
+		return Ok(());
+	}
+
+	let id = snapshot
+		.clone_snapshot(clone.name.as_deref().unwrap_or_default())
+		.await?;
+	let subvolumes = snapshot
+		.clones()
+		.await
+		.wrap_err("failed to get snapshot clones")?
+		.into_iter()
+		.find(|(clone_id, _, _)| *clone_id == id)
+		.map(|(_, _, subvolumes)| subvolumes)
+		.unwrap_or_default();
+	println!(
+		"{} snapshot {} as clone {}",
+		"Cloned".green(),
+		clone.snapshot.blue(),
+		id.cyan()
+	);
+	for subvolume in subvolumes {
+		println!("\t{}", subvolume.green());
+	}
+
+	Ok(())
+}
@@ -1,10 +1,16 @@
 // SPDX-License-Identifier: MPL-2.0
 use crate::{
 	args::{CliArgs, CliCreate},
+	job::follow_job,
 	util::yes_no_prompt,
 };
-use color_eyre::{eyre::WrapErr, Result};
+use color_eyre::{
+	eyre::{eyre, WrapErr},
+	Result,
+};
 use owo_colors::OwoColorize;
+use std::collections::HashMap;
+use zbus::zvariant::{OwnedObjectPath, Value};
 use zbus_pop_snapshot::{PopSnapshotProxy, SnapshotProxy};
 
 pub async fn create(args: &CliArgs, create: &CliCreate) -> Result<()> {
@@ -14,6 +20,32 @@ pub async fn create(args: &CliArgs, create: &CliCreate) -> Result<()> {
 	let proxy = PopSnapshotProxy::new(&connection)
 		.await
 		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
+	if create.dry_run {
+		let subvolumes = proxy
+			.resolve_subvolumes(create.subvolumes.as_deref().unwrap_or_default())
+			.await
+			.wrap_err("failed to resolve subvolumes")?;
+		println!("A new snapshot would capture the following subvolumes:");
+		for subvolume in subvolumes {
+			println!("\t{}", subvolume.green());
+		}
+		if create.subvolumes.is_none() {
+			let skipped = proxy
+				.eligible_subvolumes()
+				.await
+				.wrap_err("failed to get eligible subvolumes")?
+				.into_iter()
+				.filter(|(_, eligible, _)| !eligible)
+				.collect::<Vec<_>>();
+			if !skipped.is_empty() {
+				println!("The following subvolumes would be skipped:");
+				for (subvolume, _, reason) in skipped {
+					println!("\t{} ({})", subvolume.yellow(), reason.dimmed());
+				}
+			}
+		}
+		return Ok(());
+	}
 	let is_sure = args.yes || {
 		println!(
 			"Are you {} you want to {} a snapshot?",
@@ -52,14 +84,34 @@ pub async fn create(args: &CliArgs, create: &CliCreate) -> Result<()> {
 		}
 	}
 
-	let new_snapshot_path = proxy
-		.create_snapshot(
-			create.name.clone().into(),
-			create.description.clone().into(),
-			create.subvolumes.clone().into(),
-		)
+	let mut options = HashMap::new();
+	if let Some(name) = &create.name {
+		options.insert("name", Value::from(name.as_str()));
+	}
+	if let Some(description) = &create.description {
+		options.insert("description", Value::from(description.as_str()));
+	}
+	if let Some(subvolumes) = &create.subvolumes {
+		options.insert("subvolumes", Value::from(subvolumes.clone()));
+	}
+	if let Some(trigger) = &create.trigger {
+		options.insert("trigger", Value::from(trigger.as_str()));
+	}
+	if !create.tags.is_empty() {
+		options.insert("tags", Value::from(create.tags.clone()));
+	}
+	let job_path = proxy
+		.start_create_snapshot_with_options(options)
 		.await
-		.wrap_err("failed to create snapshot")?;
+		.wrap_err("failed to start creating snapshot")?;
+	let new_snapshot_uuid = follow_job(&connection, &job_path).await?;
+	let new_snapshot_path = Option::<OwnedObjectPath>::from(
+		proxy
+			.find_snapshot(&new_snapshot_uuid)
+			.await
+			.wrap_err("failed to find new snapshot")?,
+	)
+	.ok_or_else(|| eyre!("new snapshot {} not found", new_snapshot_uuid))?;
 	let new_snapshot = SnapshotProxy::builder(&connection)
 		.path(&new_snapshot_path)
 		.wrap_err("failed to connect to new snapshot path")?
@@ -82,6 +134,10 @@ pub async fn create(args: &CliArgs, create: &CliCreate) -> Result<()> {
 		.subvolumes()
 		.await
 		.wrap_err("failed to get snapshot subvolumes")?;
+	let tags = new_snapshot
+		.tags()
+		.await
+		.wrap_err("failed to get snapshot tags")?;
 
 	println!("Created snapshot {}", uuid.blue());
 	println!(
@@ -104,6 +160,9 @@ pub async fn create(args: &CliArgs, create: &CliCreate) -> Result<()> {
 		}
 		.dimmed(),
 	);
+	if !tags.is_empty() {
+		println!("\t{}: {}", "Tags".bold(), tags.join(", ").cyan());
+	}
 	print!("\t{}: ", "Subvolumes".bold());
 	let mut iter = subvolumes.iter().peekable();
 	while let Some(subvolume) = iter.next() {
@@ -1,12 +1,14 @@
 // SPDX-License-Identifier: MPL-2.0
 use crate::{
 	args::{CliArgs, CliDelete},
+	job::follow_job,
 	util::yes_no_prompt,
 };
 use color_eyre::{eyre::WrapErr, Result};
 use owo_colors::OwoColorize;
+use std::collections::HashMap;
 use zbus::zvariant::OwnedObjectPath;
-use zbus_pop_snapshot::{PopSnapshotProxy, SnapshotProxy};
+use zbus_pop_snapshot::{Error, PopSnapshotProxy, SnapshotProxy};
 
 pub async fn delete(args: &CliArgs, delete: &CliDelete) -> Result<()> {
 	let connection = zbus::Connection::system()
@@ -15,18 +17,13 @@ pub async fn delete(args: &CliArgs, delete: &CliDelete) -> Result<()> {
 	let proxy = PopSnapshotProxy::new(&connection)
 		.await
 		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
-	let snapshot_path = match Option::<OwnedObjectPath>::from(
+	let snapshot_path = Option::<OwnedObjectPath>::from(
 		proxy
 			.find_snapshot(&delete.snapshot)
 			.await
 			.wrap_err("failed to list snapshots")?,
-	) {
-		Some(path) => path,
-		None => {
-			println!("Snapshot {} not found", delete.snapshot.blue());
-			return Ok(());
-		}
-	};
+	)
+	.ok_or_else(|| Error::NotFound(format!("snapshot {} not found", delete.snapshot)))?;
 
 	let snapshot = SnapshotProxy::builder(&connection)
 		.path(&snapshot_path)
@@ -59,12 +56,27 @@ pub async fn delete(args: &CliArgs, delete: &CliDelete) -> Result<()> {
 		return Ok(());
 	}
 
-	snapshot
-		.delete()
-		.await
-		.wrap_err_with(|| format!("failed to delete snapshot {}", delete.snapshot))?;
+	if delete.force {
+		snapshot
+			.force_delete()
+			.await
+			.wrap_err_with(|| format!("failed to delete snapshot {}", delete.snapshot))?;
+	} else {
+		let job_path = snapshot
+			.start_delete(HashMap::new())
+			.await
+			.wrap_err_with(|| format!("failed to start deleting snapshot {}", delete.snapshot))?;
+		follow_job(&connection, &job_path)
+			.await
+			.wrap_err_with(|| format!("failed to delete snapshot {}", delete.snapshot))?;
+	}
 
-	println!("{} snapshot {}", "Deleted".red(), delete.snapshot.blue());
+	println!(
+		"{} snapshot {} to the trash, use `pop-snapshot trash restore {}` to bring it back",
+		"Moved".red(),
+		delete.snapshot.blue(),
+		delete.snapshot
+	);
 
 	Ok(())
 }
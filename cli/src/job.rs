@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MPL-2.0
+use color_eyre::{
+	eyre::{eyre, WrapErr},
+	Result,
+};
+use futures_util::StreamExt;
+use owo_colors::OwoColorize;
+use std::io::Write;
+use zbus::{zvariant::OwnedObjectPath, Connection};
+use zbus_pop_snapshot::{Error, JobProxy};
+
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// Follows a job until it's done, showing its progress,
+/// and returns its result.
+///
+/// Pressing Ctrl-C in the meantime cancels the job.
+pub async fn follow_job(connection: &Connection, path: &OwnedObjectPath) -> Result<String> {
+	let job = JobProxy::builder(connection)
+		.path(path)
+		.wrap_err_with(|| format!("failed to connect to job {}", path.as_str()))?
+		.build()
+		.await
+		.wrap_err_with(|| format!("failed to connect to job {}", path.as_str()))?;
+	let mut progress_changes = job.receive_progress_changed().await;
+	let mut subvolume_changes = job.receive_current_subvolume_changed().await;
+	let mut state_changes = job.receive_state_changed().await;
+	let mut finished = job
+		.receive_finished()
+		.await
+		.wrap_err("failed to listen for the job finishing")?;
+	// The job might have finished before we started listening.
+	let state = job.state().await.wrap_err("failed to get job state")?;
+	let (result, error_name, error) = if state == "queued" || state == "running" {
+		show_progress(&job).await?;
+		let finished = loop {
+			tokio::select! {
+				Some(_) = progress_changes.next() => show_progress(&job).await?,
+				Some(_) = subvolume_changes.next() => show_progress(&job).await?,
+				Some(_) = state_changes.next() => show_progress(&job).await?,
+				finished = finished.next() => break finished,
+				result = tokio::signal::ctrl_c() => {
+					result.wrap_err("failed to listen for Ctrl-C")?;
+					// Rather than leaving the daemon working on an abandoned
+					// request, ask it to stop, and wait for it to clean up.
+					eprintln!();
+					match job.cancel().await {
+						Ok(()) => eprintln!(
+							"{}",
+							"Cancelling, waiting for the job to stop safely...".yellow()
+						),
+						Err(err) => eprintln!("{}: {}", "Failed to cancel job".red(), err),
+					}
+				}
+			}
+		};
+		eprintln!();
+		let args = finished
+			.ok_or_else(|| eyre!("the job disappeared before it finished"))?
+			.args()
+			.wrap_err("failed to parse Finished signal")?;
+		(
+			args.result().to_string(),
+			args.error_name().to_string(),
+			args.error().to_string(),
+		)
+	} else {
+		(
+			job.result().await.wrap_err("failed to get job result")?,
+			job.error_name()
+				.await
+				.wrap_err("failed to get job error name")?,
+			job.error().await.wrap_err("failed to get job error")?,
+		)
+	};
+	if error_name.is_empty() {
+		Ok(result)
+	} else {
+		Err(Error::from_name(&error_name, error).into())
+	}
+}
+
+async fn show_progress(job: &JobProxy<'_>) -> Result<()> {
+	if job.state().await.wrap_err("failed to get job state")? == "queued" {
+		eprint!(
+			"\r{}\x1b[K",
+			"Waiting for other operations to finish...".dimmed()
+		);
+		return std::io::stderr().flush().wrap_err("failed to flush stderr");
+	}
+	let progress = job
+		.progress()
+		.await
+		.wrap_err("failed to get job progress")?;
+	let subvolume = job
+		.current_subvolume()
+		.await
+		.wrap_err("failed to get current subvolume")?;
+	let filled = (progress.clamp(0.0, 1.0) * PROGRESS_BAR_WIDTH as f64).round() as usize;
+	eprint!(
+		"\r[{}{}] {:>3}% {}\x1b[K",
+		"#".repeat(filled).green(),
+		"-".repeat(PROGRESS_BAR_WIDTH - filled).dimmed(),
+		(progress * 100.0).round() as u32,
+		subvolume.dimmed()
+	);
+	std::io::stderr().flush().wrap_err("failed to flush stderr")
+}
@@ -1,78 +1,271 @@
 // SPDX-License-Identifier: MPL-2.0
-use color_eyre::{eyre::WrapErr, Result};
+use crate::args::CliList;
+use color_eyre::{
+	eyre::{eyre, WrapErr},
+	Result,
+};
 use owo_colors::OwoColorize;
-use zbus_pop_snapshot::{PopSnapshotProxy, SnapshotProxy};
+use std::collections::HashMap;
+use zbus::zvariant::{OwnedValue, Value};
+use zbus_pop_snapshot::{PopSnapshotObjectManagerProxy, PopSnapshotProxy, SNAPSHOT_INTERFACE};
 
-pub async fn list() -> Result<()> {
+/// The properties of a snapshot object, by name.
+type Properties = HashMap<String, OwnedValue>;
+
+/// Gets a property of a snapshot object from `GetManagedObjects`.
+fn property<T>(properties: &Properties, name: &str) -> Result<T>
+where
+	T: TryFrom<Value<'static>>,
+	T::Error: std::error::Error + Send + Sync + 'static,
+{
+	let value = properties
+		.get(name)
+		.ok_or_else(|| eyre!("missing the {} property", name))?;
+	T::try_from(Value::from(value.clone())).wrap_err_with(|| format!("invalid {} property", name))
+}
+
+struct SnapshotInfo {
+	uuid: String,
+	name: String,
+	description: String,
+	creation_time: String,
+	subvolumes: Vec<String>,
+	/// Subvolumes on filesystems other than the root one, by filesystem UUID.
+	other_filesystems: Vec<(String, Vec<String>)>,
+	tags: Vec<String>,
+	pair_id: String,
+	pair_kind: String,
+	state: String,
+	metadata_error: String,
+	protected: bool,
+	clones: Vec<(String, String, Vec<String>)>,
+	parent: String,
+	replaced_by: String,
+	/// Whether the system is running from, or will boot into, this snapshot.
+	base: Option<&'static str>,
+}
+
+impl SnapshotInfo {
+	fn new(properties: &Properties) -> Result<Self> {
+		Ok(Self {
+			uuid: property(properties, "Uuid")?,
+			name: property(properties, "Name")?,
+			description: property(properties, "Description")?,
+			creation_time: property(properties, "CreationTime")?,
+			subvolumes: property(properties, "Subvolumes")?,
+			other_filesystems: property::<Vec<(String, Vec<String>)>>(properties, "Filesystems")?
+				.into_iter()
+				.skip(1)
+				.collect(),
+			tags: property(properties, "Tags")?,
+			pair_id: property(properties, "PairId")?,
+			pair_kind: property(properties, "PairKind")?,
+			state: property(properties, "State")?,
+			metadata_error: property(properties, "MetadataError")?,
+			protected: property(properties, "Protected")?,
+			clones: property(properties, "Clones")?,
+			parent: property(properties, "Parent")?,
+			replaced_by: property(properties, "ReplacedBy")?,
+			base: None,
+		})
+	}
+
+	fn print(&self, indent: &str) {
+		if self.pair_kind.is_empty() {
+			println!("{}Snapshot {}", indent, self.uuid.green());
+		} else {
+			println!(
+				"{}Snapshot {} ({})",
+				indent,
+				self.uuid.green(),
+				self.pair_kind.bold()
+			);
+		}
+		if let Some(base) = self.base {
+			println!("{}\t{}", indent, base.magenta().bold());
+		}
+		if self.state == "broken" {
+			println!(
+				"{}\t{}: {}",
+				indent,
+				"Broken metadata".red().bold(),
+				self.metadata_error.red()
+			);
+		}
+		if !self.replaced_by.is_empty() {
+			println!(
+				"{}\t{}: {}",
+				indent,
+				"Replaced by".bold(),
+				self.replaced_by.green()
+			);
+		}
+		if self.protected {
+			println!("{}\t{}", indent, "Protected".yellow().bold());
+		}
+		if !self.name.is_empty() {
+			println!("{}\t{}: {}", indent, "Name".bold(), self.name.dimmed());
+		}
+		if !self.description.is_empty() {
+			println!(
+				"{}\t{}: {}",
+				indent,
+				"Description".bold(),
+				self.description.dimmed()
+			);
+		}
+		if !self.tags.is_empty() {
+			println!(
+				"{}\t{}: {}",
+				indent,
+				"Tags".bold(),
+				self.tags.join(", ").cyan()
+			);
+		}
+		print!("{}\t{}: ", indent, "Subvolumes".bold());
+		let mut subvolumes = self.subvolumes.iter().peekable();
+		while let Some(subvolume) = subvolumes.next() {
+			print!("{} ", subvolume.green());
+			match subvolumes.peek() {
+				Some(_) => print!(", "),
+				None => println!(),
+			}
+		}
+		for (uuid, subvolumes) in &self.other_filesystems {
+			println!(
+				"{}\t{} {}: {}",
+				indent,
+				"Subvolumes on".bold(),
+				uuid.blue(),
+				subvolumes.join(", ").green()
+			);
+		}
+		for (id, name, subvolumes) in &self.clones {
+			print!("{}\t{} {}", indent, "Clone".bold(), id.cyan());
+			if !name.is_empty() {
+				print!(" ({})", name.dimmed());
+			}
+			println!(": {}", subvolumes.join(", ").green());
+		}
+	}
+}
+
+pub async fn list(list: &CliList) -> Result<()> {
 	let connection = zbus::Connection::system()
 		.await
 		.wrap_err("failed to connect to D-Bus system bus")?;
 	let proxy = PopSnapshotProxy::new(&connection)
 		.await
 		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
-	let snapshot_objects = proxy
-		.snapshots()
+	let object_manager = PopSnapshotObjectManagerProxy::new(&connection)
+		.await
+		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
+	let snapshot_objects = object_manager
+		.get_managed_objects()
 		.await
 		.wrap_err("failed to list snapshots")?;
-	for snapshot_path in snapshot_objects {
-		// We don't use ? here, as we want to gracefully handle a snapshot not existing for some reason.
-		let snapshot = match SnapshotProxy::builder(&connection).path(&snapshot_path) {
-			Ok(snapshot) => snapshot,
+	let mut snapshots = Vec::with_capacity(snapshot_objects.len());
+	for (snapshot_path, interfaces) in snapshot_objects {
+		let info = match interfaces
+			.get(SNAPSHOT_INTERFACE)
+			.ok_or_else(|| eyre!("not a snapshot"))
+			.and_then(SnapshotInfo::new)
+		{
+			Ok(info) => info,
+			// A single odd snapshot shouldn't stop the rest from being listed.
 			Err(err) => {
 				println!(
 					"{} to get info for the snapshot object {}:\n\t{}",
 					"Failed".red(),
 					snapshot_path.as_str().blue(),
-					err.red()
+					format!("{:#}", err).red()
 				);
 				continue;
 			}
 		};
-		let snapshot = match snapshot.build().await {
-			Ok(snapshot) => snapshot,
-			Err(err) => {
-				println!(
-					"{} to get info for the snapshot object {}:\n\t{}",
-					"Failed".red(),
-					snapshot_path.as_str().blue(),
-					err.red()
-				);
-				continue;
-			}
-		};
-		let uuid = snapshot
-			.uuid()
-			.await
-			.wrap_err("failed to get snapshot UUID")?;
-		println!("Snapshot {}", uuid.green());
-		let name = snapshot
-			.name()
-			.await
-			.wrap_err("failed to get snapshot name")?;
-		let description = snapshot
-			.description()
-			.await
-			.wrap_err("failed to get snapshot description")?;
-		let subvolumes = snapshot
-			.subvolumes()
-			.await
-			.wrap_err("failed to get snapshot subvolumes")?;
-		if !name.is_empty() {
-			println!("\t{}: {}", "Name".bold(), name.dimmed());
-		}
-		if !description.is_empty() {
-			println!("\t{}: {}", "Description".bold(), description.dimmed());
-		}
-		print!("\t{}: ", "Subvolumes".bold());
-		let mut subvolumes = subvolumes.iter().peekable();
-		while let Some(subvolume) = subvolumes.next() {
-			print!("{} ", subvolume.green());
-			match subvolumes.peek() {
-				Some(_) => print!(", "),
-				None => println!(),
-			}
+		if list.tags.iter().all(|tag| info.tags.contains(tag)) {
+			snapshots.push(info);
+		}
+	}
+	mark_system_base(&proxy, &mut snapshots).await;
+	// RFC3339 timestamps in UTC sort chronologically.
+	snapshots.sort_by(|a, b| a.creation_time.cmp(&b.creation_time));
+
+	if list.tree {
+		// Snapshots whose parent isn't listed are shown at the top level.
+		for root in snapshots.iter().filter(|snapshot| {
+			!snapshots
+				.iter()
+				.any(|parent| parent.uuid == snapshot.parent)
+		}) {
+			print_tree(&snapshots, root, 0);
 		}
+		return Ok(());
+	}
+
+	let mut printed_pairs = Vec::new();
+	for snapshot in &snapshots {
+		if snapshot.pair_id.is_empty() {
+			snapshot.print("");
+			continue;
+		}
+		if printed_pairs.contains(&snapshot.pair_id) {
+			continue;
+		}
+		println!("Snapshot pair {}", snapshot.pair_id.cyan());
+		for paired in snapshots
+			.iter()
+			.filter(|paired| paired.pair_id == snapshot.pair_id)
+		{
+			paired.print("\t");
+		}
+		printed_pairs.push(snapshot.pair_id.clone());
 	}
 
 	Ok(())
 }
+
+fn print_tree(snapshots: &[SnapshotInfo], snapshot: &SnapshotInfo, depth: usize) {
+	snapshot.print(&"\t".repeat(depth));
+	for child in snapshots
+		.iter()
+		.filter(|child| child.parent == snapshot.uuid)
+	{
+		print_tree(snapshots, child, depth + 1);
+	}
+}
+
+/// Marks the snapshots the system is running from, and will boot into.
+///
+/// This is informational, so failing to work it out isn't fatal.
+async fn mark_system_base(proxy: &PopSnapshotProxy<'_>, snapshots: &mut [SnapshotInfo]) {
+	let (current_base, next_boot_base, restore_pending) = match tokio::try_join!(
+		proxy.current_base(),
+		proxy.next_boot_base(),
+		proxy.restore_pending()
+	) {
+		Ok(base) => base,
+		Err(err) => {
+			println!(
+				"{} to find which snapshot the system is running from:\n\t{}",
+				"Failed".red(),
+				err.red()
+			);
+			return;
+		}
+	};
+	for snapshot in snapshots.iter_mut() {
+		if snapshot.uuid == current_base {
+			snapshot.base = Some("Currently running from this snapshot");
+		}
+		if restore_pending && snapshot.uuid == next_boot_base {
+			snapshot.base = Some("Restored, reboot to run from this snapshot");
+		}
+	}
+	if restore_pending {
+		println!(
+			"{}: a snapshot has been restored, but the system hasn't been rebooted into it yet",
+			"Note".yellow().bold()
+		);
+	}
+}
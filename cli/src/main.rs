@@ -1,30 +1,84 @@
 // SPDX-License-Identifier: MPL-2.0
 mod args;
+mod clone;
 mod create;
 mod delete;
+mod job;
 mod list;
+mod protect;
 mod restore;
+mod trash;
 pub(crate) mod util;
+mod watch;
 
 use self::args::{CliArgs, CliSubcommand};
 use clap::Parser;
-use color_eyre::{eyre::WrapErr, Result};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use zbus_pop_snapshot::Error;
+
+/// Exit codes for the errors the daemon can report.
+/// Anything else exits with 1, and usage errors with 2.
+const EXIT_FAILED: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_BUSY: i32 = 4;
+const EXIT_PERMISSION_DENIED: i32 = 5;
+const EXIT_NO_SPACE: i32 = 6;
+const EXIT_INVALID_SUBVOLUME: i32 = 7;
+/// The same as being interrupted by Ctrl-C.
+const EXIT_CANCELLED: i32 = 130;
 
 #[tokio::main]
 async fn main() -> Result<()> {
 	color_eyre::install()?;
 
 	let args = CliArgs::parse();
+	if let Err(err) = run(&args).await {
+		eprintln!("Error: {:?}", err);
+		std::process::exit(exit_code(&err));
+	}
+	Ok(())
+}
+
+async fn run(args: &CliArgs) -> Result<()> {
 	match &args.subcommand {
-		CliSubcommand::List => list::list().await.wrap_err("failed to list snapshots"),
-		CliSubcommand::Create(create) => create::create(&args, create)
+		CliSubcommand::List(list) => list::list(list).await.wrap_err("failed to list snapshots"),
+		CliSubcommand::Create(create) => create::create(args, create)
 			.await
 			.wrap_err("failed to create snapshot"),
-		CliSubcommand::Delete(delete) => delete::delete(&args, delete)
+		CliSubcommand::Delete(delete) => delete::delete(args, delete)
 			.await
 			.wrap_err("failed to delete snapshot"),
-		CliSubcommand::Restore(restore) => restore::restore(&args, restore)
+		CliSubcommand::Restore(restore) => restore::restore(args, restore)
 			.await
 			.wrap_err("failed to restore snapshot"),
+		CliSubcommand::Protect(protect) => protect::protect(protect, true)
+			.await
+			.wrap_err("failed to protect snapshot"),
+		CliSubcommand::Unprotect(protect) => protect::protect(protect, false)
+			.await
+			.wrap_err("failed to unprotect snapshot"),
+		CliSubcommand::Clone(clone) => clone::clone(clone)
+			.await
+			.wrap_err("failed to clone snapshot"),
+		CliSubcommand::Trash(trash) => trash::trash(args, trash)
+			.await
+			.wrap_err("failed to manage trash"),
+		CliSubcommand::Watch => watch::watch().await.wrap_err("failed to watch for changes"),
 	}
 }
+
+/// Picks the exit code for the first error from the daemon
+/// that caused `err`.
+fn exit_code(err: &Report) -> i32 {
+	err.chain()
+		.find_map(|cause| cause.downcast_ref::<Error>())
+		.map_or(EXIT_FAILED, |err| match err {
+			Error::NotFound(_) => EXIT_NOT_FOUND,
+			Error::Busy(_) => EXIT_BUSY,
+			Error::PermissionDenied(_) => EXIT_PERMISSION_DENIED,
+			Error::NoSpace(_) => EXIT_NO_SPACE,
+			Error::InvalidSubvolume(_) => EXIT_INVALID_SUBVOLUME,
+			Error::Cancelled(_) => EXIT_CANCELLED,
+			Error::ZBus(_) | Error::Failed(_) => EXIT_FAILED,
+		})
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::args::CliProtect;
+use color_eyre::{eyre::WrapErr, Result};
+use owo_colors::OwoColorize;
+use zbus::zvariant::OwnedObjectPath;
+use zbus_pop_snapshot::{Error, PopSnapshotProxy, SnapshotProxy};
+
+pub async fn protect(protect: &CliProtect, protected: bool) -> Result<()> {
+	let connection = zbus::Connection::system()
+		.await
+		.wrap_err("failed to connect to D-Bus system bus")?;
+	let proxy = PopSnapshotProxy::new(&connection)
+		.await
+		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
+	let snapshot_path = Option::<OwnedObjectPath>::from(
+		proxy
+			.find_snapshot(&protect.snapshot)
+			.await
+			.wrap_err("failed to list snapshots")?,
+	)
+	.ok_or_else(|| Error::NotFound(format!("snapshot {} not found", protect.snapshot)))?;
+
+	let snapshot = SnapshotProxy::builder(&connection)
+		.path(&snapshot_path)
+		.wrap_err_with(|| format!("failed to connect to snapshot {}", snapshot_path.as_str()))?
+		.build()
+		.await
+		.wrap_err_with(|| format!("failed to connect to snapshot {}", snapshot_path.as_str()))?;
+
+	if protected {
+		snapshot.protect().await?;
+		println!(
+			"{} snapshot {}",
+			"Protected".green(),
+			protect.snapshot.blue()
+		);
+	} else {
+		snapshot.unprotect().await?;
+		println!(
+			"{} snapshot {}",
+			"Unprotected".yellow(),
+			protect.snapshot.blue()
+		);
+	}
+
+	Ok(())
+}
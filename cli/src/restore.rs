@@ -1,12 +1,14 @@
 // SPDX-License-Identifier: MPL-2.0
 use crate::{
 	args::{CliArgs, CliRestore},
+	job::follow_job,
 	util::yes_no_prompt,
 };
 use color_eyre::{eyre::WrapErr, Result};
 use owo_colors::OwoColorize;
+use std::collections::HashMap;
 use zbus::zvariant::OwnedObjectPath;
-use zbus_pop_snapshot::{PopSnapshotProxy, SnapshotProxy};
+use zbus_pop_snapshot::{Error, PopSnapshotProxy, SnapshotProxy};
 
 pub async fn restore(args: &CliArgs, restore: &CliRestore) -> Result<()> {
 	let connection = zbus::Connection::system()
@@ -15,18 +17,13 @@ pub async fn restore(args: &CliArgs, restore: &CliRestore) -> Result<()> {
 	let proxy = PopSnapshotProxy::new(&connection)
 		.await
 		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
-	let snapshot_path = match Option::<OwnedObjectPath>::from(
+	let snapshot_path = Option::<OwnedObjectPath>::from(
 		proxy
 			.find_snapshot(&restore.snapshot)
 			.await
 			.wrap_err("failed to list snapshots")?,
-	) {
-		Some(path) => path,
-		None => {
-			println!("Snapshot {} not found", restore.snapshot.blue());
-			return Ok(());
-		}
-	};
+	)
+	.ok_or_else(|| Error::NotFound(format!("snapshot {} not found", restore.snapshot)))?;
 
 	let snapshot = SnapshotProxy::builder(&connection)
 		.path(&snapshot_path)
@@ -59,8 +56,11 @@ pub async fn restore(args: &CliArgs, restore: &CliRestore) -> Result<()> {
 		return Ok(());
 	}
 
-	snapshot
-		.restore()
+	let job_path = snapshot
+		.start_restore(HashMap::new())
+		.await
+		.wrap_err_with(|| format!("failed to start restoring snapshot {}", restore.snapshot))?;
+	let backup_uuid = follow_job(&connection, &job_path)
 		.await
 		.wrap_err_with(|| format!("failed to restore snapshot {}", restore.snapshot))?;
 
@@ -69,6 +69,10 @@ pub async fn restore(args: &CliArgs, restore: &CliRestore) -> Result<()> {
 		restore.snapshot.blue(),
 		"reboot".bold()
 	);
+	println!(
+		"The previous state of the system was saved as snapshot {}",
+		backup_uuid.blue()
+	);
 
 	Ok(())
 }
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: MPL-2.0
+use crate::{
+	args::{CliArgs, CliTrash, CliTrashSubcommand},
+	job::follow_job,
+	util::yes_no_prompt,
+};
+use color_eyre::{eyre::WrapErr, Result};
+use owo_colors::OwoColorize;
+use zbus::Connection;
+use zbus_pop_snapshot::PopSnapshotProxy;
+
+pub async fn trash(args: &CliArgs, trash: &CliTrash) -> Result<()> {
+	let connection = zbus::Connection::system()
+		.await
+		.wrap_err("failed to connect to D-Bus system bus")?;
+	let proxy = PopSnapshotProxy::new(&connection)
+		.await
+		.wrap_err("failed to connect to Pop!_OS snapshot service")?;
+	match &trash.subcommand {
+		CliTrashSubcommand::List => list(&proxy).await,
+		CliTrashSubcommand::Restore(restore) => {
+			proxy
+				.undelete(&restore.snapshot)
+				.await
+				.wrap_err_with(|| format!("failed to undelete snapshot {}", restore.snapshot))?;
+			println!(
+				"{} snapshot {}",
+				"Undeleted".green(),
+				restore.snapshot.blue()
+			);
+			Ok(())
+		}
+		CliTrashSubcommand::Purge => purge(args, &connection, &proxy).await,
+	}
+}
+
+async fn list(proxy: &PopSnapshotProxy<'_>) -> Result<()> {
+	let trash = proxy.list_trash().await.wrap_err("failed to list trash")?;
+	if trash.is_empty() {
+		println!("The trash is empty");
+		return Ok(());
+	}
+	for (uuid, name, description, creation_time, deleted_time, subvolumes) in trash {
+		println!("Snapshot {}", uuid.green());
+		if !name.is_empty() {
+			println!("\t{}: {}", "Name".bold(), name.dimmed());
+		}
+		if !description.is_empty() {
+			println!("\t{}: {}", "Description".bold(), description.dimmed());
+		}
+		println!("\t{}: {}", "Created".bold(), creation_time);
+		println!("\t{}: {}", "Deleted".bold(), deleted_time.red());
+		println!(
+			"\t{}: {}",
+			"Subvolumes".bold(),
+			subvolumes.join(", ").green()
+		);
+	}
+	Ok(())
+}
+
+async fn purge(
+	args: &CliArgs,
+	connection: &Connection,
+	proxy: &PopSnapshotProxy<'_>,
+) -> Result<()> {
+	let is_sure = args.yes || {
+		println!(
+			"Are you {} you want to {} every snapshot in the trash?",
+			"SURE".bold(),
+			"permanently delete".red()
+		);
+		println!(
+			"Press '{}' for {}, or any other key to {}",
+			"y".green().bold(),
+			"yes".green(),
+			"cancel".red()
+		);
+		yes_no_prompt()
+	};
+	if !is_sure {
+		println!("Alright, {} purging the trash", "not".bold());
+		return Ok(());
+	}
+	let job_path = proxy
+		.start_purge_trash()
+		.await
+		.wrap_err("failed to start purging the trash")?;
+	follow_job(connection, &job_path)
+		.await
+		.wrap_err("failed to purge trash")?;
+	println!("{} the trash", "Purged".red());
+	Ok(())
+}
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: MPL-2.0
+use color_eyre::{eyre::WrapErr, Result};
+use futures_util::StreamExt;
+use owo_colors::OwoColorize;
+use std::collections::HashMap;
+use zbus::{
+	fdo::DBusProxy,
+	zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+	Connection, MessageStream,
+};
+use zbus_pop_snapshot::{InterfaceProperties, SNAPSHOT_INTERFACE};
+
+const SERVICE_INTERFACE: &str = "com.system76.PopSnapshot";
+const SNAPSHOT_PATH_PREFIX: &str = "/com/system76/PopSnapshot/Snapshot/";
+
+/// The signals the daemon sends when snapshots, or the daemon itself, change.
+const MATCH_RULES: &[&str] = &[
+	"type='signal',sender='com.system76.PopSnapshot',\
+	 interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',\
+	 path_namespace='/com/system76/PopSnapshot'",
+	"type='signal',sender='com.system76.PopSnapshot',\
+	 interface='org.freedesktop.DBus.ObjectManager',path='/com/system76/PopSnapshot'",
+];
+
+/// Prints changes to snapshots as they happen, until interrupted.
+pub async fn watch() -> Result<()> {
+	let connection = Connection::system()
+		.await
+		.wrap_err("failed to connect to D-Bus system bus")?;
+	// Start receiving before adding the match rules, so nothing is missed.
+	let mut messages = MessageStream::from(&connection);
+	let dbus = DBusProxy::new(&connection)
+		.await
+		.wrap_err("failed to connect to D-Bus")?;
+	for rule in MATCH_RULES {
+		dbus.add_match(rule)
+			.await
+			.wrap_err("failed to listen for changes")?;
+	}
+	eprintln!("{}", "Watching for changes, press Ctrl-C to stop".dimmed());
+	while let Some(message) = messages.next().await {
+		let message = message.wrap_err("failed to receive message")?;
+		let (interface, member, path) =
+			match (message.interface(), message.member(), message.path()) {
+				(Some(interface), Some(member), Some(path)) => (interface, member, path),
+				_ => continue,
+			};
+		match (interface.as_str(), member.as_str()) {
+			("org.freedesktop.DBus.Properties", "PropertiesChanged") => {
+				let (interface, changed, invalidated) = message
+					.body::<(String, HashMap<String, OwnedValue>, Vec<String>)>()
+					.wrap_err("failed to parse PropertiesChanged signal")?;
+				// Jobs report their progress here too, which `watch` isn't about.
+				if interface != SERVICE_INTERFACE && interface != SNAPSHOT_INTERFACE {
+					continue;
+				}
+				let object = describe_object(&path);
+				let mut changed = changed.into_iter().collect::<Vec<_>>();
+				changed.sort_by(|(a, _), (b, _)| a.cmp(b));
+				for (name, value) in changed {
+					println!(
+						"{} {}: {} = {}",
+						"Changed".yellow().bold(),
+						object,
+						name.bold(),
+						format_value(&value)
+					);
+				}
+				for name in invalidated {
+					println!("{} {}: {}", "Changed".yellow().bold(), object, name.bold());
+				}
+			}
+			("org.freedesktop.DBus.ObjectManager", "InterfacesAdded") => {
+				let (path, _) = message
+					.body::<(OwnedObjectPath, InterfaceProperties)>()
+					.wrap_err("failed to parse InterfacesAdded signal")?;
+				println!("{} {}", "Added".green().bold(), describe_object(&path));
+			}
+			("org.freedesktop.DBus.ObjectManager", "InterfacesRemoved") => {
+				let (path, _) = message
+					.body::<(OwnedObjectPath, Vec<String>)>()
+					.wrap_err("failed to parse InterfacesRemoved signal")?;
+				println!("{} {}", "Removed".red().bold(), describe_object(&path));
+			}
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+/// Names an object of the daemon, using the UUID for snapshots.
+fn describe_object(path: &ObjectPath<'_>) -> String {
+	match path.as_str().strip_prefix(SNAPSHOT_PATH_PREFIX) {
+		Some(uuid) => format!("snapshot {}", uuid.replace('_', "-").green()),
+		None if path.as_str() == "/com/system76/PopSnapshot" => "service".to_owned(),
+		None => path.to_string(),
+	}
+}
+
+fn format_value(value: &Value<'_>) -> String {
+	let join = |values: &[Value<'_>]| {
+		values
+			.iter()
+			.map(format_value)
+			.collect::<Vec<_>>()
+			.join(", ")
+	};
+	match value {
+		Value::Str(value) => format!("{:?}", value.as_str()),
+		Value::Bool(value) => value.to_string(),
+		Value::U32(value) => value.to_string(),
+		Value::F64(value) => value.to_string(),
+		Value::ObjectPath(value) => value.to_string(),
+		Value::Array(array) => format!("[{}]", join(array.get())),
+		Value::Structure(structure) => format!("({})", join(structure.fields())),
+		Value::Value(value) => format_value(value),
+		value => format!("{:?}", value),
+	}
+}
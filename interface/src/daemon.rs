@@ -1,8 +1,9 @@
 // SPDX-License-Identifier: MPL-2.0
 
+use std::collections::HashMap;
 use zbus::{
 	dbus_proxy, fdo,
-	zvariant::{Optional, OwnedObjectPath},
+	zvariant::{Optional, OwnedObjectPath, Value},
 };
 
 #[dbus_proxy(
@@ -12,22 +13,112 @@ use zbus::{
 )]
 pub trait PopSnapshot {
 	/// The list of snapshots that are currently registered with the daemon.
+	///
+	/// Each snapshot is at `/com/system76/PopSnapshot/Snapshot/<UUID>`,
+	/// with the dashes in its UUID replaced by underscores,
+	/// so its path stays the same across restarts.
 	#[dbus_proxy(property)]
 	fn snapshots(&self) -> fdo::Result<Vec<OwnedObjectPath>>;
 
-	/// Finds the snapshot with the given UUID.
-	fn find_snapshot(&self, uuid: &str) -> fdo::Result<Optional<OwnedObjectPath>>;
+	/// The UUID of the snapshot the running system was restored from,
+	/// or an empty string if it's unknown or was never restored.
+	#[dbus_proxy(property)]
+	fn current_base(&self) -> fdo::Result<String>;
+
+	/// The UUID of the snapshot the system will be running from after
+	/// the next reboot, or an empty string if it's unknown or was never restored.
+	#[dbus_proxy(property)]
+	fn next_boot_base(&self) -> fdo::Result<String>;
+
+	/// Whether a snapshot has been restored, but not yet booted into.
+	#[dbus_proxy(property)]
+	fn restore_pending(&self) -> fdo::Result<bool>;
+
+	/// How many operations are waiting for others to finish before they can start.
+	#[dbus_proxy(property)]
+	fn queued_jobs(&self) -> fdo::Result<u32>;
+
+	/// Every subvolume on the root filesystem that could be snapshotted,
+	/// whether it's snapshotted by default, and if not, why: `excluded`,
+	/// `swapfile`, `nocow`, `cache` or `clone`.
+	#[dbus_proxy(property)]
+	fn eligible_subvolumes(&self) -> fdo::Result<Vec<(String, bool, String)>>;
+
+	/// Finds the snapshot with the given UUID, if it exists.
+	fn find_snapshot(&self, uuid: &str) -> crate::Result<Optional<OwnedObjectPath>>;
 
 	/// Takes a snapshot of the current system state.
+	///
+	/// Subvolumes may be given as glob patterns of their paths,
+	/// or as the absolute path they're mounted at.
 	fn create_snapshot(
 		&self,
 		name: Optional<String>,
 		description: Optional<String>,
 		subvolumes: Optional<Vec<String>>,
-	) -> fdo::Result<OwnedObjectPath>;
+	) -> crate::Result<OwnedObjectPath>;
+
+	/// Like `create_snapshot`, but with the snapshot described by a dictionary
+	/// of options, any of which may be left out:
+	///
+	/// - `name`, `description` (strings) and `subvolumes` (an array of strings),
+	///   as for `create_snapshot`.
+	/// - `trigger` (a string): one of `manual` (the default), `schedule`, `apt` or `import`.
+	/// - `tags` (an array of strings).
+	fn create_snapshot_with_options(
+		&self,
+		options: HashMap<&str, Value<'_>>,
+	) -> crate::Result<OwnedObjectPath>;
+
+	/// Like `create_snapshot`, but returns the path of a job
+	/// tracking the creation straight away.
+	fn start_create_snapshot(
+		&self,
+		name: Optional<String>,
+		description: Optional<String>,
+		subvolumes: Optional<Vec<String>>,
+	) -> crate::Result<OwnedObjectPath>;
+
+	/// Like `create_snapshot_with_options`, but returns the path of a job
+	/// tracking the creation straight away.
+	fn start_create_snapshot_with_options(
+		&self,
+		options: HashMap<&str, Value<'_>>,
+	) -> crate::Result<OwnedObjectPath>;
+
+	/// Works out which subvolumes a snapshot would capture, given the same
+	/// subvolumes that would be passed to `create_snapshot`.
+	///
+	/// An empty list uses the configured defaults.
+	fn resolve_subvolumes(&self, spec: &[String]) -> crate::Result<Vec<String>>;
+
+	/// Takes the "pre" snapshot of a pair around an operation,
+	/// returning the ID of the new pair.
+	fn begin_snapshot_pair(&self, description: &str) -> crate::Result<String>;
+
+	/// Takes the "post" snapshot of the given pair,
+	/// returning the path of the new snapshot.
+	fn end_snapshot_pair(&self, pair_id: &str) -> crate::Result<OwnedObjectPath>;
+
+	/// Lists the snapshots in the trash, in the order they were deleted,
+	/// as tuples of their UUID, name, description, creation time,
+	/// deletion time and subvolumes.
+	fn list_trash(
+		&self,
+	) -> crate::Result<Vec<(String, String, String, String, String, Vec<String>)>>;
+
+	/// Moves a snapshot out of the trash, returning its new path.
+	fn undelete(&self, uuid: &str) -> crate::Result<OwnedObjectPath>;
+
+	/// Permanently deletes every snapshot in the trash.
+	fn purge_trash(&self) -> crate::Result<()>;
+
+	/// Like `purge_trash`, but returns the path of a job
+	/// tracking the purge straight away.
+	fn start_purge_trash(&self) -> crate::Result<OwnedObjectPath>;
 
 	/// Reloads the configuration of the pop-snapshot daemon.
-	fn reload_config(&self) -> fdo::Result<()>;
+	fn reload_config(&self) -> crate::Result<()>;
 
 	/// Emits a signal when a snapshot is created.
 	#[dbus_proxy(signal)]
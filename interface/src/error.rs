@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use zbus::{fdo, DBusError};
+
+/// The errors returned by the snapshot service, beyond the standard ones.
+#[derive(Debug, DBusError)]
+#[dbus_error(prefix = "com.system76.PopSnapshot.Error")]
+pub enum Error {
+	#[dbus_error(zbus_error)]
+	ZBus(zbus::Error),
+	/// The snapshot, clone or pair asked for doesn't exist.
+	NotFound(String),
+	/// Another operation kept the service busy for longer than this one
+	/// was willing to wait. Trying again later may succeed.
+	Busy(String),
+	/// The caller isn't allowed to do this.
+	PermissionDenied(String),
+	/// There isn't enough space left on the filesystem.
+	NoSpace(String),
+	/// A subvolume given to the operation doesn't exist,
+	/// or can't be snapshotted.
+	InvalidSubvolume(String),
+	/// The operation was cancelled before it finished.
+	Cancelled(String),
+	/// Anything else that went wrong.
+	Failed(String),
+}
+
+impl Error {
+	/// Recreates an error from its D-Bus name, as found in a job's `ErrorName`.
+	///
+	/// Unknown names become [`Error::Failed`].
+	pub fn from_name(name: &str, message: String) -> Self {
+		match name.strip_prefix("com.system76.PopSnapshot.Error.") {
+			Some("NotFound") => Self::NotFound(message),
+			Some("Busy") => Self::Busy(message),
+			Some("PermissionDenied") => Self::PermissionDenied(message),
+			Some("NoSpace") => Self::NoSpace(message),
+			Some("InvalidSubvolume") => Self::InvalidSubvolume(message),
+			Some("Cancelled") => Self::Cancelled(message),
+			_ => Self::Failed(message),
+		}
+	}
+
+	/// The same kind of error, with a different message.
+	pub fn with_message(&self, message: String) -> Self {
+		match self {
+			Self::ZBus(_) | Self::Failed(_) => Self::Failed(message),
+			Self::NotFound(_) => Self::NotFound(message),
+			Self::Busy(_) => Self::Busy(message),
+			Self::PermissionDenied(_) => Self::PermissionDenied(message),
+			Self::NoSpace(_) => Self::NoSpace(message),
+			Self::InvalidSubvolume(_) => Self::InvalidSubvolume(message),
+			Self::Cancelled(_) => Self::Cancelled(message),
+		}
+	}
+}
+
+impl From<fdo::Error> for Error {
+	fn from(err: fdo::Error) -> Self {
+		Self::ZBus(zbus::Error::FDO(Box::new(err)))
+	}
+}
+
+/// Lets property getters, which can only fail with standard errors,
+/// use `?` on these errors.
+impl From<Error> for fdo::Error {
+	fn from(err: Error) -> Self {
+		match err {
+			Error::ZBus(zbus::Error::FDO(err)) => *err,
+			err => Self::Failed(err.to_string()),
+		}
+	}
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
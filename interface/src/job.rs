@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use zbus::{dbus_proxy, fdo};
+
+#[dbus_proxy(
+	interface = "com.system76.PopSnapshot.Job",
+	default_service = "com.system76.PopSnapshot"
+)]
+pub trait Job {
+	/// What this job is doing: `create`, `restore`, `delete` or `purge`.
+	#[dbus_proxy(property)]
+	fn operation(&self) -> fdo::Result<String>;
+
+	/// `queued`, `running`, `succeeded`, `failed` or `cancelled`.
+	#[dbus_proxy(property)]
+	fn state(&self) -> fdo::Result<String>;
+
+	/// How far along this job is, from 0 to 1.
+	#[dbus_proxy(property)]
+	fn progress(&self) -> fdo::Result<f64>;
+
+	/// The subvolume currently being worked on, or an empty string.
+	#[dbus_proxy(property)]
+	fn current_subvolume(&self) -> fdo::Result<String>;
+
+	/// Once the job has succeeded, the UUID of the snapshot it created,
+	/// the backup snapshot made by a restore, or the deleted snapshot.
+	/// For purges, the UUIDs of the purged snapshots, separated by spaces.
+	#[dbus_proxy(property)]
+	fn result(&self) -> fdo::Result<String>;
+
+	/// The D-Bus name of the error the job failed with, if it did.
+	/// [`crate::Error::from_name`] turns it back into an error.
+	#[dbus_proxy(property)]
+	fn error_name(&self) -> fdo::Result<String>;
+
+	/// Why the job failed, if it did.
+	#[dbus_proxy(property)]
+	fn error(&self) -> fdo::Result<String>;
+
+	/// Asks for the job to stop as soon as it safely can, rolling back
+	/// anything it's done so far.
+	///
+	/// Restores can only be cancelled before they start replacing subvolumes,
+	/// and purges of the trash stop before the next snapshot.
+	fn cancel(&self) -> crate::Result<()>;
+
+	/// Emitted when the job is done, with its `result`, `error_name` and `error`.
+	#[dbus_proxy(signal)]
+	fn finished(&self, result: &str, error_name: &str, error: &str) -> fdo::Result<()>;
+}
@@ -1,7 +1,13 @@
 // SPDX-License-Identifier: MPL-2.0
 
 mod daemon;
+mod error;
+mod job;
+mod object_manager;
 mod snapshot;
 
 pub use daemon::*;
+pub use error::{Error, Result};
+pub use job::*;
+pub use object_manager::*;
 pub use snapshot::*;
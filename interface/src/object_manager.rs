@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use std::collections::HashMap;
+use zbus::{
+	dbus_proxy, fdo,
+	zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+};
+
+/// The name of the interface snapshot objects implement.
+pub const SNAPSHOT_INTERFACE: &str = "com.system76.PopSnapshot.Snapshot";
+
+/// The properties of an object, by interface and then by property name.
+pub type InterfaceProperties = HashMap<String, HashMap<String, OwnedValue>>;
+
+/// Every snapshot object, along with all of its properties.
+pub type ManagedObjects = HashMap<OwnedObjectPath, InterfaceProperties>;
+
+#[dbus_proxy(
+	interface = "org.freedesktop.DBus.ObjectManager",
+	default_service = "com.system76.PopSnapshot",
+	default_path = "/com/system76/PopSnapshot"
+)]
+pub trait PopSnapshotObjectManager {
+	/// Gets every snapshot object and all of its properties in one go.
+	fn get_managed_objects(&self) -> fdo::Result<ManagedObjects>;
+
+	/// Emitted when a snapshot object is added, with all of its properties.
+	#[dbus_proxy(signal)]
+	fn interfaces_added(
+		&self,
+		object_path: ObjectPath<'_>,
+		interfaces_and_properties: HashMap<&str, HashMap<&str, Value<'_>>>,
+	) -> fdo::Result<()>;
+
+	/// Emitted when a snapshot object is removed.
+	#[dbus_proxy(signal)]
+	fn interfaces_removed(
+		&self,
+		object_path: ObjectPath<'_>,
+		interfaces: Vec<&str>,
+	) -> fdo::Result<()>;
+}
@@ -1,6 +1,10 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use zbus::{dbus_proxy, fdo};
+use std::collections::HashMap;
+use zbus::{
+	dbus_proxy, fdo,
+	zvariant::{OwnedObjectPath, Value},
+};
 
 #[dbus_proxy(
 	interface = "com.system76.PopSnapshot.Snapshot",
@@ -29,18 +33,140 @@ pub trait Snapshot {
 	#[dbus_proxy(property)]
 	fn set_description(&self, description: &str) -> fdo::Result<()>;
 
-	/// A list of subvolumes that have been captured by this snapshot.
+	/// Labels that can be used to find and filter snapshots.
+	#[dbus_proxy(property)]
+	fn tags(&self) -> fdo::Result<Vec<String>>;
+
+	/// Sets the tags of this snapshot.
+	#[dbus_proxy(property)]
+	fn set_tags(&self, tags: &[String]) -> fdo::Result<()>;
+
+	/// A list of subvolumes on the root filesystem that have been captured by this snapshot.
 	#[dbus_proxy(property)]
 	fn subvolumes(&self) -> fdo::Result<Vec<String>>;
 
+	/// What was done with nested subvolumes when this snapshot was taken:
+	/// `skip`, `recursive` or `carry-over`. Empty if unknown.
+	#[dbus_proxy(property)]
+	fn nested_subvolumes(&self) -> fdo::Result<String>;
+
+	/// The filesystems this snapshot has subvolumes on, as tuples of
+	/// their UUID and the snapshotted subvolumes.
+	///
+	/// The root filesystem comes first; its UUID is empty if it wasn't known.
+	#[dbus_proxy(property)]
+	fn filesystems(&self) -> fdo::Result<Vec<(String, Vec<String>)>>;
+
 	/// The unique identifier of this snapshot.
 	#[dbus_proxy(property)]
 	fn uuid(&self) -> fdo::Result<String>;
 
+	/// The ID of the pre/post pair this snapshot is part of, if any.
+	#[dbus_proxy(property)]
+	fn pair_id(&self) -> fdo::Result<String>;
+
+	/// Whether this is the "pre" or "post" snapshot of its pair, if any.
+	#[dbus_proxy(property)]
+	fn pair_kind(&self) -> fdo::Result<String>;
+
+	/// The UUID of the snapshot the live system had last been restored from
+	/// when this snapshot was taken, if any.
+	#[dbus_proxy(property)]
+	fn parent(&self) -> fdo::Result<String>;
+
+	/// For a backup taken while restoring, the UUID of the snapshot
+	/// that was restored in its place.
+	#[dbus_proxy(property)]
+	fn replaced_by(&self) -> fdo::Result<String>;
+
+	/// What triggered this snapshot: `manual`, `schedule`, `apt`,
+	/// `restore-backup` or `import`. Empty if unknown.
+	#[dbus_proxy(property)]
+	fn trigger(&self) -> fdo::Result<String>;
+
+	/// The user ID of the process that requested this snapshot,
+	/// or `u32::MAX` if unknown.
+	#[dbus_proxy(property)]
+	fn sender_uid(&self) -> fdo::Result<u32>;
+
+	/// The name of the process that requested this snapshot, if known.
+	#[dbus_proxy(property)]
+	fn sender_process(&self) -> fdo::Result<String>;
+
+	/// The hostname of the system this snapshot was taken on, if known.
+	#[dbus_proxy(property)]
+	fn hostname(&self) -> fdo::Result<String>;
+
+	/// The kernel that was running when this snapshot was taken, if known.
+	#[dbus_proxy(property)]
+	fn kernel_version(&self) -> fdo::Result<String>;
+
+	/// The OS version, from `/etc/os-release`, when this snapshot was taken, if known.
+	#[dbus_proxy(property)]
+	fn os_version(&self) -> fdo::Result<String>;
+
+	/// Whether this snapshot's metadata could be read: `ok` or `broken`.
+	#[dbus_proxy(property)]
+	fn state(&self) -> fdo::Result<String>;
+
+	/// Why this snapshot's metadata couldn't be read, if it's broken.
+	#[dbus_proxy(property)]
+	fn metadata_error(&self) -> fdo::Result<String>;
+
+	/// Whether this snapshot is protected from deletion.
+	#[dbus_proxy(property)]
+	fn protected(&self) -> fdo::Result<bool>;
+
+	/// Protects this snapshot from deletion.
+	fn protect(&self) -> crate::Result<()>;
+
+	/// Allows this snapshot to be deleted again.
+	/// Only root may do this.
+	fn unprotect(&self) -> crate::Result<()>;
+
+	/// The writable clones made of this snapshot,
+	/// as tuples of their ID, name and top-level subvolumes.
+	#[dbus_proxy(property)]
+	fn clones(&self) -> fdo::Result<Vec<(String, String, Vec<String>)>>;
+
+	/// Creates writable copies of this snapshot's subvolumes as new
+	/// top-level subvolumes, returning the ID of the clone.
+	///
+	/// Clones aren't included in new snapshots by default.
+	/// While this snapshot has clones, it isn't purged from the trash.
+	#[dbus_proxy(name = "Clone")]
+	fn clone_snapshot(&self, name: &str) -> crate::Result<String>;
+
+	/// Deletes the subvolumes of a clone of this snapshot.
+	fn delete_clone(&self, id: &str) -> crate::Result<()>;
+
+	/// Rebuilds the metadata of a broken snapshot from its subvolumes.
+	fn repair_metadata(&self) -> crate::Result<()>;
+
 	/// Restores the system to this snapshot,
 	/// creating a backup snapshot of the current system state in the process.
-	fn restore(&self) -> fdo::Result<()>;
+	fn restore(&self) -> crate::Result<()>;
+
+	/// Like `restore`, but returns the path of a job
+	/// tracking the restore straight away.
+	///
+	/// The only option is `trigger`, as for `create_snapshot_with_options`,
+	/// which is passed to the hooks.
+	fn start_restore(&self, options: HashMap<&str, Value<'_>>) -> crate::Result<OwnedObjectPath>;
+
+	/// Moves this snapshot to the trash, unless it's protected.
+	///
+	/// It's permanently deleted once the trash's grace period is over.
+	fn delete(&self) -> crate::Result<()>;
+
+	/// Like `delete`, but returns the path of a job
+	/// tracking the deletion straight away.
+	///
+	/// The only option is `trigger`, as for `create_snapshot_with_options`,
+	/// which is passed to the hooks.
+	fn start_delete(&self, options: HashMap<&str, Value<'_>>) -> crate::Result<OwnedObjectPath>;
 
-	/// Deletes this snapshot permanently.
-	fn delete(&self) -> fdo::Result<()>;
+	/// Moves this snapshot to the trash, even if it's protected.
+	/// Only root may do this.
+	fn force_delete(&self) -> crate::Result<()>;
 }
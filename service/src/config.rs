@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: MPL-2.0
+use crate::snapshot::nested::NestedSubvolumePolicy;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Debug, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
@@ -10,15 +11,41 @@ pub struct Config {
 	///
 	/// Defaults to `@snapshots/pop-snapshots`.
 	pub snapshot_path: PathBuf,
+	/// The name of the subvolume the system boots from,
+	/// such as `@root` on Pop!_OS or `@` on Ubuntu.
+	///
+	/// Defaults to the subvolume mounted at `/`.
+	pub root_subvolume: Option<String>,
 	/// A list of subvolumes to exclude by default.
 	///
+	/// Each entry is either a glob pattern of subvolume paths, such as
+	/// `@*/var/cache/**`, or the absolute path a subvolume is mounted at,
+	/// such as `/var/log`. Subvolumes nested within a match are excluded too.
+	///
 	/// `@snapshots` will *always* be excluded, regardless of this list.
 	pub exclude_subvolumes: Vec<String>,
-	/// A list of subvolumes to include by default.
+	/// A list of subvolumes to include by default,
+	/// in the same form as `exclude_subvolumes`.
 	///
 	/// This will take precedence over `subvolumes_to_exclude` if both are
 	/// specified.
 	pub include_subvolumes: Option<Vec<String>>,
+	/// Known cache, container and VM image locations, in the same form as
+	/// `exclude_subvolumes`, which aren't snapshotted by default.
+	///
+	/// Subvolumes holding an active swapfile, or marked NOCOW, are
+	/// skipped too. Subvolumes that are explicitly included are never skipped.
+	pub cache_subvolumes: Vec<String>,
+	/// What to do with subvolumes nested within other snapshotted
+	/// subvolumes: `skip`, `recursive` or `carry-over`.
+	///
+	/// Defaults to `carry-over`, which leaves them untouched by restores.
+	pub nested_subvolumes: NestedSubvolumePolicy,
+	/// Other btrfs filesystems to snapshot alongside the root one.
+	///
+	/// Their snapshots are kept at `snapshot_path` and `trash_path`
+	/// on each filesystem.
+	pub filesystems: Vec<FilesystemConfig>,
 	/// The logging filter to use.
 	///
 	/// Can be any [`EnvFilter`](https://docs.rs/tracing-subscriber/0.3.11/tracing_subscriber/filter/struct.EnvFilter.html#directives)
@@ -28,15 +55,118 @@ pub struct Config {
 	///
 	/// Defaults to "info".
 	pub log_level: String,
+	/// Whether to flush the filesystem to disk right before snapshotting.
+	///
+	/// Defaults to true.
+	pub sync_filesystem: bool,
+	/// Commands to run to quiesce applications (such as flushing databases
+	/// or pausing containers) before snapshotting, and to resume them afterwards.
+	pub quiesce_hooks: Vec<QuiesceHook>,
+	/// The directory containing the `{pre,post}-{create,delete,restore}.d`
+	/// directories of executable hooks to run around snapshot operations.
+	///
+	/// Defaults to `/etc/pop-snapshot/hooks`.
+	pub hooks_path: PathBuf,
+	/// Whether to rewrite snapshot metadata that was written with an
+	/// older schema version in the current version, once it's been migrated.
+	///
+	/// Defaults to false, which keeps the files readable by older versions.
+	pub rewrite_migrated_metadata: bool,
+	/// The path (relative to the base subvolume of the btrfs partition)
+	/// to the directory deleted snapshots are moved into.
+	///
+	/// This must be within the same subvolume as `snapshot_path`.
+	///
+	/// Defaults to `@snapshots/pop-snapshots-trash`.
+	pub trash_path: PathBuf,
+	/// How long, in days, deleted snapshots are kept before they're
+	/// permanently removed.
+	///
+	/// Defaults to 7 days.
+	pub trash_grace_period: u64,
+	/// The percentage of the filesystem that should be kept free.
+	/// If there's less free space than this, deleted snapshots are
+	/// permanently removed, oldest first, before their grace period is over.
+	///
+	/// Defaults to 10%.
+	pub trash_min_free_space: u8,
+	/// How long, in seconds, an operation waits for the ones requested
+	/// before it to finish, before giving up because the service is busy.
+	///
+	/// Defaults to 600 seconds.
+	pub queue_timeout: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FilesystemConfig {
+	/// The UUID of the filesystem, as in `/dev/disk/by-uuid`.
+	pub uuid: String,
+	/// The subvolumes on this filesystem to snapshot by default.
+	pub subvolumes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QuiesceHook {
+	/// A name for this hook, used in logs and snapshot metadata.
+	pub name: String,
+	/// The command (and its arguments) to run before snapshotting.
+	pub freeze: Vec<String>,
+	/// The command (and its arguments) to run after snapshotting,
+	/// regardless of whether snapshotting succeeded.
+	#[serde(default)]
+	pub thaw: Vec<String>,
+	/// How long, in seconds, each command may run before it's killed.
+	///
+	/// Defaults to 30 seconds.
+	#[serde(default = "QuiesceHook::default_timeout")]
+	pub timeout: u64,
+	/// Whether snapshot creation should be aborted if the freeze command fails.
+	///
+	/// Defaults to false.
+	#[serde(default)]
+	pub required: bool,
+}
+
+impl Config {
+	pub fn queue_timeout(&self) -> Duration {
+		Duration::from_secs(self.queue_timeout)
+	}
+}
+
+impl QuiesceHook {
+	fn default_timeout() -> u64 {
+		30
+	}
 }
 
 impl Default for Config {
 	fn default() -> Self {
 		Self {
 			snapshot_path: "@snapshots/pop-snapshots".into(),
+			root_subvolume: None,
 			exclude_subvolumes: vec!["@home".into()],
 			include_subvolumes: None,
+			cache_subvolumes: vec![
+				"*/var/cache".into(),
+				"*/var/tmp".into(),
+				"*/var/lib/docker".into(),
+				"*/var/lib/containers".into(),
+				"*/var/lib/machines".into(),
+				"*/var/lib/libvirt/images".into(),
+			],
+			nested_subvolumes: NestedSubvolumePolicy::default(),
+			filesystems: Vec::new(),
 			log_level: "info".into(),
+			sync_filesystem: true,
+			quiesce_hooks: Vec::new(),
+			hooks_path: "/etc/pop-snapshot/hooks".into(),
+			rewrite_migrated_metadata: false,
+			trash_path: "@snapshots/pop-snapshots-trash".into(),
+			trash_grace_period: 7,
+			trash_min_free_space: 10,
+			queue_timeout: 600,
 		}
 	}
 }
@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::snapshot::metadata::SnapshotMetadata;
+use anyhow::{anyhow, Context, Result};
+use std::{
+	fmt,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+use tokio::{fs, process::Command};
+use uuid::Uuid;
+
+/// How long a single hook script may run before it's killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+	Pre,
+	Post,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookOperation {
+	Create,
+	Delete,
+	Restore,
+}
+
+impl fmt::Display for HookStage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Pre => "pre",
+			Self::Post => "post",
+		})
+	}
+}
+
+impl fmt::Display for HookOperation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Create => "create",
+			Self::Delete => "delete",
+			Self::Restore => "restore",
+		})
+	}
+}
+
+/// Information about the operation that is passed to hook scripts
+/// through environment variables.
+pub struct HookContext<'a> {
+	pub operation: HookOperation,
+	pub uuid: Uuid,
+	pub subvolumes: &'a [String],
+	pub trigger: &'a str,
+	/// The backup snapshot made while restoring, if any.
+	pub backup_uuid: Option<Uuid>,
+	/// Whether the operation failed after the pre-hooks ran,
+	/// so that post-hooks can undo what they did.
+	pub failed: bool,
+	/// Whether the failure was because the operation was cancelled.
+	pub cancelled: bool,
+}
+
+impl<'a> HookContext<'a> {
+	pub fn new(operation: HookOperation, snapshot: &'a SnapshotMetadata, trigger: &'a str) -> Self {
+		Self {
+			operation,
+			uuid: snapshot.uuid,
+			subvolumes: &snapshot.subvolumes,
+			trigger,
+			backup_uuid: None,
+			failed: false,
+			cancelled: false,
+		}
+	}
+}
+
+/// Runs every executable in `<hooks_path>/<stage>-<operation>.d/`,
+/// in lexical order of their file names.
+///
+/// A failing pre-hook aborts and returns an error, so that the operation
+/// isn't performed. Failing post-hooks are only logged, as the operation
+/// has already happened by then.
+pub async fn run_hooks(
+	hooks_path: &Path,
+	stage: HookStage,
+	context: &HookContext<'_>,
+) -> Result<()> {
+	let hook_dir = hooks_path.join(format!("{}-{}.d", stage, context.operation));
+	let hooks = match (list_hooks(&hook_dir).await, stage) {
+		(Ok(hooks), _) => hooks,
+		(Err(err), HookStage::Pre) => return Err(err),
+		(Err(err), HookStage::Post) => {
+			warn!("Failed to list hooks in {}: {err:#}", hook_dir.display());
+			return Ok(());
+		}
+	};
+	for hook in hooks {
+		info!(
+			"Running {stage}-{} hook {}",
+			context.operation,
+			hook.display()
+		);
+		let result = run_hook(&hook, stage, context).await;
+		match (result, stage) {
+			(Ok(()), _) => {}
+			(Err(err), HookStage::Pre) => {
+				return Err(err).with_context(|| {
+					format!(
+						"pre-{} hook {} failed, aborting",
+						context.operation,
+						hook.display()
+					)
+				});
+			}
+			(Err(err), HookStage::Post) => {
+				warn!(
+					"post-{} hook {} failed: {err:#}",
+					context.operation,
+					hook.display()
+				);
+			}
+		}
+	}
+	Ok(())
+}
+
+async fn list_hooks(hook_dir: &Path) -> Result<Vec<PathBuf>> {
+	if !hook_dir.is_dir() {
+		return Ok(Vec::new());
+	}
+	let mut hooks = Vec::new();
+	let mut dir = fs::read_dir(hook_dir)
+		.await
+		.with_context(|| format!("failed to read directory {}", hook_dir.display()))?;
+	while let Some(entry) = dir
+		.next_entry()
+		.await
+		.context("failed to read directory entry")?
+	{
+		let path = entry.path();
+		let metadata = match fs::metadata(&path).await {
+			Ok(metadata) => metadata,
+			Err(err) => {
+				warn!("Skipping hook {}: {err}", path.display());
+				continue;
+			}
+		};
+		if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+			debug!("Skipping non-executable hook {}", path.display());
+			continue;
+		}
+		hooks.push(path);
+	}
+	hooks.sort();
+	Ok(hooks)
+}
+
+async fn run_hook(hook: &Path, stage: HookStage, context: &HookContext<'_>) -> Result<()> {
+	let mut command = Command::new(hook);
+	command
+		.env("POP_SNAPSHOT_STAGE", stage.to_string())
+		.env("POP_SNAPSHOT_OPERATION", context.operation.to_string())
+		.env("POP_SNAPSHOT_UUID", context.uuid.to_string())
+		.env("POP_SNAPSHOT_SUBVOLUMES", context.subvolumes.join(" "))
+		.env("POP_SNAPSHOT_TRIGGER", context.trigger)
+		.kill_on_drop(true);
+	if let Some(backup_uuid) = context.backup_uuid {
+		command.env("POP_SNAPSHOT_BACKUP_UUID", backup_uuid.to_string());
+	}
+	if context.failed {
+		command.env("POP_SNAPSHOT_FAILED", "1");
+	}
+	if context.cancelled {
+		command.env("POP_SNAPSHOT_CANCELLED", "1");
+	}
+	let output = tokio::time::timeout(HOOK_TIMEOUT, command.output())
+		.await
+		.with_context(|| format!("timed out after {} seconds", HOOK_TIMEOUT.as_secs()))?
+		.context("failed to run hook")?;
+	if !output.status.success() {
+		return Err(anyhow!(
+			"exited with {}: {}",
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim()
+		));
+	}
+	Ok(())
+}
@@ -1,5 +1,7 @@
 // SPDX-License-Identifier: MPL-2.0
 pub(crate) mod config;
+pub(crate) mod hooks;
+pub(crate) mod mountinfo;
 pub(crate) mod service;
 pub(crate) mod snapshot;
 pub(crate) mod util;
@@ -7,35 +9,88 @@ pub(crate) mod util;
 #[macro_use]
 extern crate tracing;
 
-use crate::service::snapshot::SnapshotObject;
-use anyhow::{Context, Result};
+use crate::{
+	service::{job::start_job, snapshot::SnapshotObject},
+	snapshot::{list::BrokenSnapshot, metadata::SnapshotMetadata, progress::ProgressReporter},
+};
+use anyhow::{anyhow, Context, Result};
 use async_signals::Signals;
 use futures_util::StreamExt;
 use libc::{SIGHUP, SIGTERM};
-use std::sync::{
-	atomic::{AtomicUsize, Ordering},
-	Arc,
-};
+use std::{sync::Arc, time::Duration};
 use tokio::sync::RwLock;
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::{filter::Directive, fmt, prelude::*, EnvFilter};
-use zbus::{zvariant::OwnedObjectPath, ConnectionBuilder, ObjectServer};
+use uuid::Uuid;
+use zbus::{zvariant::OwnedObjectPath, ConnectionBuilder, ObjectServer, SignalContext};
 
-static COUNTER: AtomicUsize = AtomicUsize::new(1);
+/// How often to check the trash for snapshots to purge.
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The object path of a snapshot, which stays the same across restarts
+/// so that clients can hold on to it.
+fn snapshot_object_path(uuid: Uuid) -> OwnedObjectPath {
+	OwnedObjectPath::try_from(format!(
+		"/com/system76/PopSnapshot/Snapshot/{}",
+		uuid.to_string().replace('-', "_")
+	))
+	.expect("UUIDs with underscores are valid object path elements")
+}
 
 async fn create_new_snapshot(
 	object_server: &ObjectServer,
 	snapshot_object: SnapshotObject,
 ) -> Result<OwnedObjectPath> {
-	let new_id = COUNTER.fetch_add(1, Ordering::SeqCst);
-	let id = OwnedObjectPath::try_from(format!("/com/system76/PopSnapshot/Snapshot/{}", new_id))?;
-	object_server
+	let id = snapshot_object_path(snapshot_object.snapshot_uuid());
+	let added = object_server
 		.at(&id, snapshot_object)
 		.await
 		.with_context(|| format!("failed to register snapshot {:?}", id))?;
+	if !added {
+		return Err(anyhow!("snapshot {:?} is already registered", id));
+	}
 	Ok(id)
 }
 
+async fn list_snapshots(
+	config: Arc<RwLock<config::Config>>,
+) -> Result<(Vec<SnapshotMetadata>, Vec<BrokenSnapshot>)> {
+	let btrfs = snapshot::MountedBtrfs::new()
+		.await
+		.context("failed to mount btrfs to list snapshots")?;
+	let config = config.read().await;
+	btrfs
+		.list_snapshots(&config.snapshot_path, config.rewrite_migrated_metadata)
+		.await
+		.context("failed to list snapshots")
+}
+
+/// Permanently deletes the snapshots in the trash whose grace period is over,
+/// or that need to go to free up space, returning their UUIDs.
+async fn purge_trash(
+	config: Arc<RwLock<config::Config>>,
+	progress: &ProgressReporter,
+) -> Result<Vec<Uuid>> {
+	let btrfs = snapshot::MountedBtrfs::new()
+		.await
+		.context("failed to mount btrfs to purge trash")?;
+	let config = config.read().await;
+	let grace_period = Duration::from_secs(config.trash_grace_period * 24 * 60 * 60);
+	let purged = btrfs
+		.purge_trash(
+			&config.trash_path,
+			grace_period,
+			config.trash_min_free_space,
+			progress,
+		)
+		.await
+		.context("failed to purge trash")?;
+	if !purged.is_empty() {
+		info!("Purged {} snapshot(s) from the trash", purged.len());
+	}
+	Ok(purged)
+}
+
 async fn reload_config(config: Arc<RwLock<config::Config>>) -> Result<()> {
 	let mut config = config.write().await;
 	*config = tokio::fs::read_to_string("/etc/pop-snapshots.toml")
@@ -74,6 +129,7 @@ async fn main() -> Result<()> {
 
 	let config = Arc::new(RwLock::new(config));
 	let service = service::SnapshotService::new(config.clone());
+	let action_queue = service.action_queue.clone();
 	let connection = ConnectionBuilder::system()
 		.context("failed to get system dbus connection")?
 		.name("com.system76.PopSnapshot")?
@@ -82,45 +138,110 @@ async fn main() -> Result<()> {
 		.await
 		.context("failed to build connection")?;
 
-	{
-		let btrfs = snapshot::MountedBtrfs::new()
-			.await
-			.context("failed to mount btrfs to list snapshots")?;
-		let snapshots = btrfs
-			.list_snapshots()
-			.await
-			.context("failed to list snapshots")?;
-		let mut snapshots_map = service.snapshots.write().await;
-		snapshots_map.reserve(snapshots.len());
-		for snapshot in snapshots {
-			let snapshot_uuid = snapshot.uuid;
-			let snapshot_object = SnapshotObject::new(
-				snapshot,
-				service.snapshots.clone(),
-				service.action_lock.clone(),
-				config.clone(),
-			);
-			let id = create_new_snapshot(&*connection.object_server(), snapshot_object)
-				.await
-				.context("failed to create new snapshot object")?;
-
-			debug!(
-				"Created new snapshot object for {} at {:?}",
-				snapshot_uuid, id
-			);
-			snapshots_map.insert(snapshot_uuid, id);
+	// Failing to list snapshots shouldn't stop the daemon from starting,
+	// otherwise nothing can be done to fix it over D-Bus.
+	match list_snapshots(config.clone()).await {
+		Ok((snapshots, broken)) => {
+			let mut snapshots_map = service.snapshots.write().await;
+			snapshots_map.reserve(snapshots.len() + broken.len());
+			let snapshot_objects = snapshots
+				.into_iter()
+				.map(|snapshot| {
+					SnapshotObject::new(
+						snapshot,
+						service.snapshots.clone(),
+						service.action_queue.clone(),
+						service.base.clone(),
+						config.clone(),
+					)
+				})
+				.chain(broken.into_iter().map(|broken| {
+					SnapshotObject::new(
+						broken.metadata,
+						service.snapshots.clone(),
+						service.action_queue.clone(),
+						service.base.clone(),
+						config.clone(),
+					)
+					.with_broken_metadata(broken.error, broken.quarantined)
+				}));
+			// Healthy snapshots come first, so that if a snapshot's metadata is
+			// both readable and in the broken directory, the readable copy wins.
+			for snapshot_object in snapshot_objects {
+				let snapshot_uuid = snapshot_object.snapshot_uuid();
+				if snapshots_map.contains_key(&snapshot_uuid) {
+					warn!("Skipping duplicate metadata of snapshot {snapshot_uuid}");
+					continue;
+				}
+				let id = match create_new_snapshot(&*connection.object_server(), snapshot_object)
+					.await
+				{
+					Ok(id) => id,
+					Err(err) => {
+						error!("Failed to create snapshot object for {snapshot_uuid}: {err:#}");
+						continue;
+					}
+				};
+
+				debug!(
+					"Created new snapshot object for {} at {:?}",
+					snapshot_uuid, id
+				);
+				snapshots_map.insert(snapshot_uuid, id);
+			}
 		}
+		Err(err) => error!("Failed to list snapshots: {:#}", err),
 	}
+	service::update_base(&service.base, &config).await;
+	let base = service.base.clone();
+	let object_manager = service::object_manager::ObjectManager::new(service.snapshots.clone());
 	connection
 		.object_server()
 		.at("/com/system76/PopSnapshot", service)
 		.await?;
+	connection
+		.object_server()
+		.at("/com/system76/PopSnapshot", object_manager)
+		.await?;
 
 	info!("Starting pop-snapshot daemon");
 
 	let mut signals = Signals::new(vec![SIGHUP, SIGTERM])
 		.context("failed to create signal handler for SIGHUP+SIGTERM")?;
 
+	tokio::spawn(service::report_queued_jobs(
+		connection.clone(),
+		action_queue.clone(),
+	));
+
+	tokio::spawn({
+		let config = config.clone();
+		let job_connection = connection.clone();
+		async move {
+			let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+			loop {
+				interval.tick().await;
+				// Run as a job, so that clients waiting behind it can see
+				// what it's doing, and cancel it.
+				let config = config.clone();
+				let action_queue = action_queue.clone();
+				let job = start_job(&job_connection, "purge", move |progress| async move {
+					let _lock = action_queue.acquire_with_progress(None, &progress).await?;
+					let purged = purge_trash(config, &progress).await?;
+					Ok(purged
+						.iter()
+						.map(Uuid::to_string)
+						.collect::<Vec<_>>()
+						.join(" "))
+				})
+				.await;
+				if let Err(err) = job {
+					error!("Failed to start purging the trash: {:#}", err);
+				}
+			}
+		}
+	});
+
 	tokio::spawn(async move {
 		let executor = connection.executor();
 		loop {
@@ -134,6 +255,19 @@ async fn main() -> Result<()> {
 				info!("Received SIGHUP, reloading config");
 				match reload_config(config.clone()).await {
 					Ok(_) => {
+						// The root subvolume or the snapshot path may have changed.
+						if service::update_base(&base, &config).await {
+							let result = match SignalContext::new(
+								&connection,
+								"/com/system76/PopSnapshot",
+							) {
+								Ok(ctxt) => service::report_base_changed(&ctxt, &base).await,
+								Err(err) => Err(err),
+							};
+							if let Err(err) = result {
+								warn!("Failed to report the system base change: {}", err);
+							}
+						}
 						continue;
 					}
 					Err(e) => {
@@ -152,3 +286,91 @@ async fn main() -> Result<()> {
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::Path;
+
+	#[test]
+	fn snapshot_paths_are_derived_from_the_uuid() {
+		let uuid = Uuid::parse_str("3f2b8c1e-5d4a-4e6f-9a7b-0c1d2e3f4a5b").unwrap();
+		assert_eq!(
+			snapshot_object_path(uuid).as_str(),
+			"/com/system76/PopSnapshot/Snapshot/3f2b8c1e_5d4a_4e6f_9a7b_0c1d2e3f4a5b"
+		);
+		assert_eq!(snapshot_object_path(uuid), snapshot_object_path(uuid));
+	}
+
+	/// The object paths a daemon starting up would register, as it would.
+	async fn list_object_paths(
+		btrfs: &snapshot::MountedBtrfs,
+		snapshot_path: &Path,
+	) -> Vec<String> {
+		let (snapshots, broken) = btrfs
+			.list_snapshots(snapshot_path, true)
+			.await
+			.expect("failed to list snapshots");
+		let mut paths = snapshots
+			.iter()
+			.chain(broken.iter().map(|broken| &broken.metadata))
+			.map(|metadata| snapshot_object_path(metadata.uuid).to_string())
+			.collect::<Vec<_>>();
+		paths.sort();
+		paths
+	}
+
+	#[tokio::test]
+	async fn snapshot_paths_are_stable_across_restarts() {
+		let btrfs = snapshot::MountedBtrfs::unmounted(
+			tempfile::tempdir().expect("failed to create temporary directory"),
+		);
+		let snapshot_path = Path::new("@snapshots/pop-snapshots");
+		let snapshot_dir = btrfs.path().join(snapshot_path);
+		tokio::fs::create_dir_all(&snapshot_dir)
+			.await
+			.expect("failed to create snapshot directory");
+
+		let healthy = SnapshotMetadata::now(
+			Some("test".to_owned()),
+			None::<String>,
+			vec!["@root".into()],
+		);
+		healthy
+			.write(&snapshot_dir)
+			.await
+			.expect("failed to write metadata");
+		// Unreadable metadata of a snapshot whose subvolumes are still there,
+		// so its metadata is reconstructed from them.
+		let reconstructed = Uuid::new_v4();
+		tokio::fs::create_dir_all(snapshot_dir.join(reconstructed.to_string()).join("@root"))
+			.await
+			.expect("failed to create snapshot subvolume directory");
+		// Unreadable metadata of a snapshot with nothing else left.
+		let orphaned = Uuid::new_v4();
+		for uuid in [reconstructed, orphaned] {
+			tokio::fs::write(
+				snapshot_dir.join(format!("{uuid}.snapshot.json")),
+				"{ not json",
+			)
+			.await
+			.expect("failed to write broken metadata");
+		}
+
+		let mut expected = [healthy.uuid, reconstructed, orphaned]
+			.into_iter()
+			.map(|uuid| snapshot_object_path(uuid).to_string())
+			.collect::<Vec<_>>();
+		expected.sort();
+		// The first start quarantines the broken metadata, and the next one
+		// finds it in the broken directory instead.
+		let first = list_object_paths(&btrfs, snapshot_path).await;
+		assert!(snapshot_dir
+			.join(snapshot::list::BROKEN_DIR)
+			.join(format!("{orphaned}.snapshot.json"))
+			.is_file());
+		let second = list_object_paths(&btrfs, snapshot_path).await;
+		assert_eq!(first, expected);
+		assert_eq!(second, expected);
+	}
+}
@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: MPL-2.0
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// A single mount, as described by a line of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+	pub mount_id: u32,
+	pub parent_id: u32,
+	/// The path, within the filesystem, that's mounted.
+	/// For btrfs, this is the path of the mounted subvolume.
+	pub root: PathBuf,
+	pub mount_point: PathBuf,
+	pub fs_type: String,
+	/// The device the filesystem is on.
+	pub source: PathBuf,
+	pub super_options: Vec<String>,
+}
+
+impl MountInfo {
+	/// Parses a single line of `/proc/self/mountinfo`, such as:
+	///
+	/// ```text
+	/// 29 1 0:26 /@ / rw,relatime shared:1 - btrfs /dev/nvme0n1p3 rw,ssd,subvolid=256,subvol=/@
+	/// ```
+	pub fn parse(line: &str) -> Result<Self> {
+		let (mount_fields, fs_fields) = line
+			.split_once(" - ")
+			.context("missing separator between mount and filesystem fields")?;
+		let mut mount_fields = mount_fields.split_whitespace();
+		let mut fs_fields = fs_fields.split_whitespace();
+		let mount_id = next_field(&mut mount_fields, "mount ID")?
+			.parse()
+			.context("invalid mount ID")?;
+		let parent_id = next_field(&mut mount_fields, "parent ID")?
+			.parse()
+			.context("invalid parent ID")?;
+		// The device number isn't needed.
+		next_field(&mut mount_fields, "device number")?;
+		let root = next_field(&mut mount_fields, "root")?.into();
+		let mount_point = next_field(&mut mount_fields, "mount point")?.into();
+		let fs_type = next_field(&mut fs_fields, "filesystem type")?;
+		let source = next_field(&mut fs_fields, "mount source")?.into();
+		let super_options = next_field(&mut fs_fields, "super options")?
+			.split(',')
+			.map(str::to_owned)
+			.collect();
+		Ok(Self {
+			mount_id,
+			parent_id,
+			root,
+			mount_point,
+			fs_type,
+			source,
+			super_options,
+		})
+	}
+
+	fn super_option(&self, key: &str) -> Option<&str> {
+		self.super_options.iter().find_map(|option| {
+			option
+				.strip_prefix(key)
+				.and_then(|value| value.strip_prefix('='))
+		})
+	}
+
+	/// The path of the mounted btrfs subvolume, relative to the top-level
+	/// subvolume, such as `@root` or `@`.
+	pub fn subvolume(&self) -> Option<&str> {
+		self.super_option("subvol")
+			.map(|subvol| subvol.trim_start_matches('/'))
+	}
+
+	/// The ID of the mounted btrfs subvolume.
+	pub fn subvolume_id(&self) -> Option<u64> {
+		self.super_option("subvolid")?.parse().ok()
+	}
+}
+
+/// Parses the contents of a mountinfo file, skipping (and logging)
+/// any lines that can't be parsed.
+pub fn parse_mountinfo(contents: &str) -> Vec<MountInfo> {
+	contents
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.filter_map(|line| match MountInfo::parse(line) {
+			Ok(mount) => Some(mount),
+			Err(err) => {
+				warn!("Failed to parse mountinfo line '{line}': {err:#}");
+				None
+			}
+		})
+		.collect()
+}
+
+/// Reads the mountinfo file describing the mounts visible to this process.
+pub async fn read_mountinfo_file() -> Result<String> {
+	fs::read_to_string("/proc/self/mountinfo")
+		.await
+		.context("failed to read /proc/self/mountinfo")
+}
+
+/// Reads the mounts visible to this process.
+pub async fn read_mountinfo() -> Result<Vec<MountInfo>> {
+	Ok(parse_mountinfo(&read_mountinfo_file().await?))
+}
+
+/// Finds what's mounted at the given mount point.
+///
+/// If several things are mounted there, the one mounted last is returned,
+/// as it's the one that's visible.
+pub fn find_mount<'a>(mounts: &'a [MountInfo], mount_point: &Path) -> Option<&'a MountInfo> {
+	mounts
+		.iter()
+		.rev()
+		.find(|mount| mount.mount_point == mount_point)
+}
+
+fn next_field<'a>(fields: &mut impl Iterator<Item = &'a str>, name: &str) -> Result<String> {
+	fields
+		.next()
+		.map(unescape)
+		.ok_or_else(|| anyhow!("missing {}", name))
+}
+
+/// Undoes the octal escaping of spaces, tabs, newlines and backslashes
+/// in mountinfo fields.
+pub(crate) fn unescape(field: &str) -> String {
+	let mut unescaped = String::with_capacity(field.len());
+	let mut chars = field.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			unescaped.push(c);
+			continue;
+		}
+		let digits = chars.clone().take(3).collect::<String>();
+		match u8::from_str_radix(&digits, 8) {
+			Ok(byte) if digits.len() == 3 => {
+				unescaped.push(char::from(byte));
+				chars.nth(2);
+			}
+			_ => unescaped.push(c),
+		}
+	}
+	unescaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MOUNTINFO: &str = include_str!("../tests/fixtures/mountinfo/btrfs.mountinfo");
+
+	fn mounts() -> Vec<MountInfo> {
+		parse_mountinfo(MOUNTINFO)
+	}
+
+	fn mount_at<'a>(mounts: &'a [MountInfo], mount_point: &str) -> &'a MountInfo {
+		find_mount(mounts, Path::new(mount_point))
+			.unwrap_or_else(|| panic!("nothing is mounted at {}", mount_point))
+	}
+
+	#[test]
+	fn skips_lines_that_cant_be_parsed() {
+		assert_eq!(mounts().len(), 8);
+	}
+
+	#[test]
+	fn parses_btrfs_subvolumes() {
+		let mounts = mounts();
+		let root = mount_at(&mounts, "/");
+		assert_eq!(
+			root,
+			&MountInfo {
+				mount_id: 22,
+				parent_id: 1,
+				root: "/@root".into(),
+				mount_point: "/".into(),
+				fs_type: "btrfs".to_owned(),
+				source: "/dev/nvme0n1p3".into(),
+				super_options: vec![
+					"rw".to_owned(),
+					"ssd".to_owned(),
+					"space_cache=v2".to_owned(),
+					"subvolid=256".to_owned(),
+					"subvol=/@root".to_owned(),
+				],
+			}
+		);
+		assert_eq!(root.subvolume(), Some("@root"));
+		assert_eq!(root.subvolume_id(), Some(256));
+		assert_eq!(mount_at(&mounts, "/home").subvolume(), Some("@home"));
+	}
+
+	#[test]
+	fn parses_the_top_level_subvolume() {
+		let mounts = mounts();
+		let top_level = mounts
+			.iter()
+			.find(|mount| mount.mount_id == 28)
+			.expect("missing top-level subvolume mount");
+		assert_eq!(top_level.subvolume(), Some(""));
+		assert_eq!(top_level.subvolume_id(), Some(5));
+	}
+
+	#[test]
+	fn skips_optional_fields() {
+		let mounts = mounts();
+		let dev = mount_at(&mounts, "/dev");
+		assert_eq!(dev.fs_type, "devtmpfs");
+		assert_eq!(dev.source, Path::new("udev"));
+		assert_eq!(dev.subvolume(), None);
+		let proc = mount_at(&mounts, "/proc");
+		assert_eq!(proc.fs_type, "proc");
+		assert_eq!(proc.super_options, vec!["rw".to_owned()]);
+	}
+
+	#[test]
+	fn unescapes_paths() {
+		let mounts = mounts();
+		let spaced = mount_at(&mounts, "/mnt/my data");
+		assert_eq!(spaced.root, Path::new("/@my data"));
+		assert_eq!(spaced.subvolume(), Some("@my data"));
+		let odd = mount_at(&mounts, "/mnt/odd");
+		assert_eq!(odd.root, Path::new("/@odd\tname\\here"));
+		assert_eq!(odd.subvolume(), Some("@odd\tname\\here"));
+	}
+
+	#[test]
+	fn finds_the_visible_mount_of_an_overmount() {
+		let mounts = mounts();
+		let log = mount_at(&mounts, "/var/log");
+		assert_eq!(log.mount_id, 29);
+		assert_eq!(log.fs_type, "tmpfs");
+		assert!(find_mount(&mounts, Path::new("/var")).is_none());
+	}
+
+	#[test]
+	fn rejects_malformed_lines() {
+		assert!(MountInfo::parse("22 1 0:21 /@root / rw,relatime btrfs /dev/sda1 rw").is_err());
+		assert!(MountInfo::parse("22 1 0:21 - btrfs /dev/sda1 rw").is_err());
+		assert!(MountInfo::parse("x 1 0:21 / / rw - btrfs /dev/sda1 rw").is_err());
+	}
+
+	#[test]
+	fn unescapes_octal_sequences() {
+		assert_eq!(unescape(r"a\040b"), "a b");
+		assert_eq!(unescape(r"a\011b"), "a\tb");
+		assert_eq!(unescape(r"a\012b"), "a\nb");
+		assert_eq!(unescape(r"a\134b"), r"a\b");
+		assert_eq!(unescape(r"\040\040"), "  ");
+	}
+
+	#[test]
+	fn leaves_incomplete_escapes_alone() {
+		assert_eq!(unescape(r"a\04"), r"a\04");
+		assert_eq!(unescape(r"a\x"), r"a\x");
+		assert_eq!(unescape(r"a\"), r"a\");
+		assert_eq!(unescape(r"a\999"), r"a\999");
+	}
+}
@@ -1,22 +1,46 @@
 // SPDX-License-Identifier: MPL-2.0
 
+pub mod job;
+pub mod object_manager;
+pub mod queue;
 pub mod snapshot;
 
-use self::snapshot::SnapshotObject;
-use crate::{config::Config, create_new_snapshot, snapshot::MountedBtrfs, util::ToFdoError};
+use self::{job::start_job, object_manager, queue::ActionQueue, snapshot::SnapshotObject};
+use crate::{
+	config::Config,
+	create_new_snapshot,
+	snapshot::{
+		base::SystemBase,
+		create::NewSnapshot,
+		metadata::{PairKind, SnapshotPair},
+		progress::ProgressReporter,
+		provenance::{Provenance, SnapshotTrigger},
+		MountedBtrfs,
+	},
+	util::{get_caller, root_subvolume, SkipReason, ToDBusError},
+};
 use anyhow::{anyhow, Context};
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::RwLock;
 use uuid::Uuid;
 use zbus::{
 	dbus_interface, fdo,
-	zvariant::{Optional, OwnedObjectPath},
-	ObjectServer, SignalContext,
+	zvariant::{Optional, OwnedObjectPath, OwnedValue, Value},
+	Connection, Interface, MessageHeader, ObjectServer, SignalContext,
 };
+use zbus_pop_snapshot::Error;
+
+/// A snapshot in the trash: its UUID, name, description,
+/// creation time, deletion time and subvolumes.
+type TrashedSnapshot = (String, String, String, String, String, Vec<String>);
 
+#[derive(Clone)]
 pub struct SnapshotService {
 	pub(crate) snapshots: Arc<RwLock<HashMap<Uuid, OwnedObjectPath>>>,
-	pub(crate) action_lock: Arc<Mutex<()>>,
+	pub(crate) action_queue: ActionQueue,
+	/// Which snapshots the system is based on, as of the last [`update_base`].
+	pub(crate) base: Arc<RwLock<SystemBase>>,
 	config: Arc<RwLock<Config>>,
 }
 
@@ -24,67 +48,625 @@ impl SnapshotService {
 	pub fn new(config: Arc<RwLock<Config>>) -> Self {
 		Self {
 			snapshots: Arc::default(),
-			action_lock: Arc::default(),
+			action_queue: ActionQueue::default(),
+			base: Arc::default(),
 			config,
 		}
 	}
-}
 
-#[dbus_interface(name = "com.system76.PopSnapshot")]
-impl SnapshotService {
-	#[dbus_interface(property)]
-	async fn snapshots(&self) -> Vec<OwnedObjectPath> {
-		self.snapshots.read().await.values().cloned().collect()
+	/// Creates a snapshot and registers its object,
+	/// returning its UUID and path.
+	async fn create_snapshot_object(
+		&self,
+		new_snapshot: NewSnapshot,
+		progress: &ProgressReporter,
+		ctxt: &SignalContext<'_>,
+		object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<(Uuid, OwnedObjectPath)> {
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self
+			.action_queue
+			.acquire_with_progress(Some(timeout), progress)
+			.await?;
+		self.create_snapshot_object_queued(new_snapshot, progress, ctxt, object_server)
+			.await
 	}
 
-	async fn create_snapshot(
-		&mut self,
-		name: Optional<String>,
-		description: Optional<String>,
-		subvolumes: Optional<Vec<String>>,
-		#[zbus(signal_context)] ctxt: SignalContext<'_>,
-		#[zbus(object_server)] object_server: &ObjectServer,
-	) -> fdo::Result<OwnedObjectPath> {
-		let _lock = match self.action_lock.try_lock() {
-			Ok(lock) => lock,
-			Err(_) => return Err(anyhow!("pop-snapshot is busy")).to_fdo_err(),
-		};
+	/// Creates a snapshot for `CreateSnapshot` and `CreateSnapshotWithOptions`.
+	async fn create_snapshot_with(
+		&self,
+		options: CreateOptions,
+		connection: &Connection,
+		hdr: &MessageHeader<'_>,
+		ctxt: &SignalContext<'_>,
+		object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let new_snapshot = options.into_new_snapshot(connection, hdr).await?;
+		let (_, path) = self
+			.create_snapshot_object(
+				new_snapshot,
+				&ProgressReporter::default(),
+				ctxt,
+				object_server,
+			)
+			.await?;
+		Ok(path)
+	}
+
+	/// Starts a job creating a snapshot, for `StartCreateSnapshot`
+	/// and `StartCreateSnapshotWithOptions`.
+	async fn start_create_snapshot_with(
+		&self,
+		options: CreateOptions,
+		connection: &Connection,
+		hdr: &MessageHeader<'_>,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let new_snapshot = options.into_new_snapshot(connection, hdr).await?;
+		let service = self.clone();
+		let job_connection = connection.clone();
+		start_job(connection, "create", move |progress| async move {
+			let ctxt = SignalContext::new(&job_connection, "/com/system76/PopSnapshot")?;
+			let object_server = job_connection.object_server();
+			let (uuid, _) = service
+				.create_snapshot_object(new_snapshot, &progress, &ctxt, &object_server)
+				.await?;
+			Ok(uuid.to_string())
+		})
+		.await
+		.context("failed to start job")
+		.to_dbus_err()
+	}
+
+	/// Permanently deletes every snapshot in the trash,
+	/// returning the UUIDs of the purged snapshots.
+	async fn purge_all_trash(
+		&self,
+		progress: &ProgressReporter,
+	) -> zbus_pop_snapshot::Result<Vec<Uuid>> {
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self
+			.action_queue
+			.acquire_with_progress(Some(timeout), progress)
+			.await?;
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let config = self.config.read().await;
+		btrfs
+			.purge_all_trash(&config.trash_path, progress)
+			.await
+			.context("failed to purge trash")
+			.to_dbus_err()
+	}
+
+	/// Like [`Self::create_snapshot_object`], for callers that have
+	/// already waited for their turn in the action queue.
+	async fn create_snapshot_object_queued(
+		&self,
+		new_snapshot: NewSnapshot,
+		progress: &ProgressReporter,
+		ctxt: &SignalContext<'_>,
+		object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<(Uuid, OwnedObjectPath)> {
 		let btrfs = MountedBtrfs::new()
 			.await
 			.context("failed to mount btrfs")
-			.to_fdo_err()?;
+			.to_dbus_err()?;
 		let snapshot = btrfs
-			.create_snapshot(name, description, subvolumes, self.config.clone())
+			.create_snapshot(new_snapshot, self.config.clone(), progress)
 			.await
 			.context("failed to create snapshot")
-			.to_fdo_err()?;
+			.to_dbus_err()?;
 		let snapshot_uuid = snapshot.uuid;
 		let snapshot_object = SnapshotObject::new(
 			snapshot,
 			self.snapshots.clone(),
-			self.action_lock.clone(),
+			self.action_queue.clone(),
+			self.base.clone(),
 			self.config.clone(),
 		);
 		let path = create_new_snapshot(object_server, snapshot_object)
 			.await
 			.with_context(|| format!("failed to register snapshot '{snapshot_uuid}'"))
-			.to_fdo_err()?;
+			.to_dbus_err()?;
 		self.snapshots
 			.write()
 			.await
 			.insert(snapshot_uuid, path.clone());
-		Self::snapshot_created(&ctxt, &snapshot_uuid.to_string())
+		let base_changed = update_base(&self.base, &self.config).await;
+		object_manager::snapshot_added(object_server, ctxt, &path)
+			.await
+			.to_dbus_err()?;
+		self.snapshots_changed(ctxt)
+			.await
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?;
+		if base_changed {
+			report_base_changed(ctxt, &self.base)
+				.await
+				.context("failed to emit PropertiesChanged signal")
+				.to_dbus_err()?;
+		}
+		Self::snapshot_created(ctxt, &snapshot_uuid.to_string())
 			.await
 			.context("failed to emit SnapshotCreated signal")
-			.to_fdo_err()?;
+			.to_dbus_err()?;
+		Ok((snapshot_uuid, path))
+	}
+}
+
+/// Gathers the provenance of a snapshot requested through the given D-Bus message.
+pub(crate) async fn collect_provenance(
+	trigger: SnapshotTrigger,
+	connection: &Connection,
+	hdr: &MessageHeader<'_>,
+) -> Provenance {
+	let caller = match get_caller(connection, hdr).await {
+		Ok(caller) => Some(caller),
+		Err(err) => {
+			warn!("Failed to identify the caller: {err:#}");
+			None
+		}
+	};
+	Provenance::collect(trigger, caller).await
+}
+
+/// Keeps the `QueuedJobs` property of the service up to date.
+pub(crate) async fn report_queued_jobs(connection: Connection, action_queue: ActionQueue) {
+	let mut queued = action_queue.watch_queued();
+	while queued.changed().await.is_ok() {
+		let result = async {
+			let service = connection
+				.object_server()
+				.interface::<_, SnapshotService>("/com/system76/PopSnapshot")
+				.await?;
+			let service_ref = service.get().await;
+			service_ref
+				.queued_jobs_changed(service.signal_context())
+				.await
+		}
+		.await;
+		if let Err(err) = result {
+			warn!("Failed to report queued jobs: {err}");
+		}
+	}
+}
+
+/// Emits `PropertiesChanged` for the `Snapshots` property of the service,
+/// for snapshots added or removed outside of the service's own methods.
+///
+/// This goes through the service's signal context rather than the service
+/// itself, as a method of the service may be holding on to it while it
+/// waits for the operation that's reporting this to finish.
+pub(crate) async fn report_snapshots_changed(
+	ctxt: &SignalContext<'_>,
+	snapshots: &RwLock<HashMap<Uuid, OwnedObjectPath>>,
+) -> zbus::Result<()> {
+	let snapshots = Value::from(
+		snapshots
+			.read()
+			.await
+			.values()
+			.map(|path| path.clone().into_inner())
+			.collect::<Vec<_>>(),
+	);
+	fdo::Properties::properties_changed(
+		ctxt,
+		<SnapshotService as Interface>::name(),
+		&HashMap::from([("Snapshots", &snapshots)]),
+		&[],
+	)
+	.await
+}
+
+/// Works out again which snapshots the system is based on, returning
+/// whether that changed.
+///
+/// This means mounting the filesystem and looking through every snapshot,
+/// so it's done at startup and after each operation that could change it,
+/// rather than whenever the properties are read. If it fails, the last
+/// known base is kept.
+pub(crate) async fn update_base(base: &RwLock<SystemBase>, config: &RwLock<Config>) -> bool {
+	let result = async {
+		let btrfs = MountedBtrfs::new().await.context("failed to mount btrfs")?;
+		let config = config.read().await;
+		let root_subvolume = root_subvolume(&config)
+			.await
+			.context("failed to find the root subvolume")?;
+		btrfs
+			.system_base(&config.snapshot_path, &root_subvolume)
+			.await
+	}
+	.await;
+	match result {
+		Ok(new_base) => {
+			let mut base = base.write().await;
+			let changed = *base != new_base;
+			*base = new_base;
+			changed
+		}
+		Err(err) => {
+			warn!("Failed to find what the system is based on: {err:#}");
+			false
+		}
+	}
+}
+
+/// Emits `PropertiesChanged` for the properties of the service that depend
+/// on which snapshots the system is based on.
+///
+/// Like [`report_snapshots_changed`], this doesn't touch the service itself.
+pub(crate) async fn report_base_changed(
+	ctxt: &SignalContext<'_>,
+	base: &RwLock<SystemBase>,
+) -> zbus::Result<()> {
+	let base = *base.read().await;
+	let current_base = Value::from(format_base(base.running));
+	let next_boot_base = Value::from(format_base(base.next_boot));
+	let restore_pending = Value::from(base.restore_pending);
+	fdo::Properties::properties_changed(
+		ctxt,
+		<SnapshotService as Interface>::name(),
+		&HashMap::from([
+			("CurrentBase", &current_base),
+			("NextBootBase", &next_boot_base),
+			("RestorePending", &restore_pending),
+		]),
+		&[],
+	)
+	.await
+}
+
+/// Formats the UUID of a base snapshot, which is empty if there's none.
+fn format_base(uuid: Option<Uuid>) -> String {
+	uuid.map(|uuid| uuid.to_string()).unwrap_or_default()
+}
+
+/// The arguments to `CreateSnapshot`, or the options given to
+/// `CreateSnapshotWithOptions`.
+#[derive(Default)]
+struct CreateOptions {
+	name: Option<String>,
+	description: Option<String>,
+	subvolumes: Option<Vec<String>>,
+	trigger: Option<String>,
+	tags: Vec<String>,
+}
+
+impl CreateOptions {
+	/// Parses the options given to `CreateSnapshotWithOptions`.
+	fn from_dict(options: HashMap<String, OwnedValue>) -> zbus_pop_snapshot::Result<Self> {
+		let mut parsed = Self::default();
+		for (key, value) in options {
+			match key.as_str() {
+				"name" => parsed.name = Some(parse_option(&key, value)?),
+				"description" => parsed.description = Some(parse_option(&key, value)?),
+				"subvolumes" => parsed.subvolumes = Some(parse_option(&key, value)?),
+				"trigger" => parsed.trigger = Some(parse_option(&key, value)?),
+				"tags" => parsed.tags = parse_option(&key, value)?,
+				_ => {
+					return Err(fdo::Error::InvalidArgs(format!("unknown option '{}'", key)).into())
+				}
+			}
+		}
+		Ok(parsed)
+	}
+
+	/// Builds a new snapshot from these options.
+	async fn into_new_snapshot(
+		self,
+		connection: &Connection,
+		hdr: &MessageHeader<'_>,
+	) -> zbus_pop_snapshot::Result<NewSnapshot> {
+		let trigger = parse_trigger(self.trigger)?;
+		Ok(NewSnapshot {
+			name: self.name,
+			description: self.description,
+			subvolumes: self.subvolumes,
+			provenance: Some(collect_provenance(trigger, connection, hdr).await),
+			tags: self.tags,
+			..NewSnapshot::default()
+		})
+	}
+}
+
+/// Parses the options given to the snapshot's `StartRestore` and
+/// `StartDelete`, of which there's only `trigger`.
+pub(crate) fn parse_operation_options(
+	options: HashMap<String, OwnedValue>,
+) -> zbus_pop_snapshot::Result<SnapshotTrigger> {
+	let mut trigger = None;
+	for (key, value) in options {
+		match key.as_str() {
+			"trigger" => trigger = Some(parse_option(&key, value)?),
+			_ => return Err(fdo::Error::InvalidArgs(format!("unknown option '{}'", key)).into()),
+		}
+	}
+	parse_trigger(trigger)
+}
+
+/// Parses a trigger given by a client, defaulting to `manual`.
+fn parse_trigger(trigger: Option<String>) -> zbus_pop_snapshot::Result<SnapshotTrigger> {
+	let trigger = match trigger {
+		Some(trigger) => trigger.parse::<SnapshotTrigger>().to_dbus_err()?,
+		None => SnapshotTrigger::Manual,
+	};
+	if trigger == SnapshotTrigger::RestoreBackup {
+		return Err(anyhow!(
+			"the restore-backup trigger is reserved for snapshots made while restoring"
+		))
+		.to_dbus_err();
+	}
+	Ok(trigger)
+}
+
+/// Converts the value of an option given to a method taking a dictionary of options.
+fn parse_option<T>(key: &str, value: OwnedValue) -> zbus_pop_snapshot::Result<T>
+where
+	T: TryFrom<Value<'static>>,
+{
+	T::try_from(Value::from(value))
+		.map_err(|_| fdo::Error::InvalidArgs(format!("option '{}' has the wrong type", key)).into())
+}
+
+#[dbus_interface(name = "com.system76.PopSnapshot")]
+impl SnapshotService {
+	#[dbus_interface(property)]
+	async fn snapshots(&self) -> Vec<OwnedObjectPath> {
+		self.snapshots.read().await.values().cloned().collect()
+	}
+
+	/// The UUID of the snapshot the running system was restored from,
+	/// or an empty string if it's unknown or was never restored.
+	#[dbus_interface(property)]
+	async fn current_base(&self) -> String {
+		format_base(self.base.read().await.running)
+	}
+
+	/// The UUID of the snapshot the system will be running from after
+	/// the next reboot, or an empty string if it's unknown or was never restored.
+	#[dbus_interface(property)]
+	async fn next_boot_base(&self) -> String {
+		format_base(self.base.read().await.next_boot)
+	}
+
+	/// Whether a snapshot has been restored, but not yet booted into.
+	#[dbus_interface(property)]
+	async fn restore_pending(&self) -> bool {
+		self.base.read().await.restore_pending
+	}
+
+	/// How many operations are waiting for others to finish before they
+	/// can start. Operations run one at a time, in the order they were requested.
+	#[dbus_interface(property)]
+	async fn queued_jobs(&self) -> u32 {
+		self.action_queue.queued()
+	}
+
+	/// Every subvolume on the root filesystem that could be snapshotted,
+	/// whether it's snapshotted by default, and if not, why: `excluded`,
+	/// `swapfile`, `nocow`, `cache` or `clone`.
+	#[dbus_interface(property)]
+	async fn eligible_subvolumes(&self) -> fdo::Result<Vec<(String, bool, String)>> {
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let config = self.config.read().await;
+		Ok(btrfs
+			.check_subvolumes(&config)
+			.await
+			.context("failed to check subvolumes")
+			.to_dbus_err()?
+			.into_iter()
+			.map(|(subvolume, reason)| {
+				(
+					subvolume,
+					reason.is_none(),
+					reason
+						.map(SkipReason::as_str)
+						.unwrap_or_default()
+						.to_owned(),
+				)
+			})
+			.collect())
+	}
+
+	async fn create_snapshot(
+		&self,
+		name: Optional<String>,
+		description: Optional<String>,
+		subvolumes: Optional<Vec<String>>,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let options = CreateOptions {
+			name: name.into(),
+			description: description.into(),
+			subvolumes: subvolumes.into(),
+			..CreateOptions::default()
+		};
+		self.create_snapshot_with(options, connection, &hdr, &ctxt, object_server)
+			.await
+	}
+
+	/// Like `CreateSnapshot`, but with the snapshot described by a dictionary
+	/// of options, any of which may be left out: `name`, `description`,
+	/// `subvolumes`, `trigger` and `tags`.
+	async fn create_snapshot_with_options(
+		&self,
+		options: HashMap<String, OwnedValue>,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let options = CreateOptions::from_dict(options)?;
+		self.create_snapshot_with(options, connection, &hdr, &ctxt, object_server)
+			.await
+	}
+
+	/// Like `CreateSnapshot`, but returns a job tracking the creation
+	/// straight away, rather than waiting for it to finish.
+	async fn start_create_snapshot(
+		&self,
+		name: Optional<String>,
+		description: Optional<String>,
+		subvolumes: Optional<Vec<String>>,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let options = CreateOptions {
+			name: name.into(),
+			description: description.into(),
+			subvolumes: subvolumes.into(),
+			..CreateOptions::default()
+		};
+		self.start_create_snapshot_with(options, connection, &hdr)
+			.await
+	}
+
+	/// Like `CreateSnapshotWithOptions`, but returns a job tracking
+	/// the creation straight away, rather than waiting for it to finish.
+	async fn start_create_snapshot_with_options(
+		&self,
+		options: HashMap<String, OwnedValue>,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let options = CreateOptions::from_dict(options)?;
+		self.start_create_snapshot_with(options, connection, &hdr)
+			.await
+	}
+
+	/// Works out which subvolumes a snapshot would capture, given the same
+	/// subvolumes that would be passed to `CreateSnapshot`.
+	///
+	/// An empty list uses the configured defaults.
+	async fn resolve_subvolumes(
+		&self,
+		spec: Vec<String>,
+	) -> zbus_pop_snapshot::Result<Vec<String>> {
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let config = self.config.read().await;
+		let spec = if spec.is_empty() { None } else { Some(spec) };
+		btrfs
+			.resolve_subvolumes(spec, &config)
+			.await
+			.context("failed to resolve subvolumes")
+			.to_dbus_err()
+	}
+
+	/// Takes the "pre" snapshot of a pair, before an operation such as
+	/// a system upgrade, returning the ID of the new pair.
+	async fn begin_snapshot_pair(
+		&self,
+		description: String,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<String> {
+		let pair = SnapshotPair {
+			id: Uuid::new_v4(),
+			kind: PairKind::Pre,
+		};
+		let new_snapshot = NewSnapshot {
+			description: Some(description),
+			pair: Some(pair),
+			provenance: Some(collect_provenance(SnapshotTrigger::Manual, connection, &hdr).await),
+			..NewSnapshot::default()
+		};
+		self.create_snapshot_object(
+			new_snapshot,
+			&ProgressReporter::default(),
+			&ctxt,
+			object_server,
+		)
+		.await?;
+		Ok(pair.id.to_string())
+	}
+
+	/// Takes the "post" snapshot of a pair that was started
+	/// with `BeginSnapshotPair`.
+	async fn end_snapshot_pair(
+		&self,
+		pair_id: &str,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let pair_id = Uuid::parse_str(pair_id)
+			.with_context(|| format!("failed to parse pair ID '{}'", pair_id))
+			.to_dbus_err()?;
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self.action_queue.acquire(Some(timeout)).await?;
+		// The pair is only checked once it's this call's turn, so that
+		// two calls can't both end it. It's read from disk, which nothing
+		// else can change in the meantime, rather than from the snapshot
+		// objects, which may be locked by calls waiting behind this one.
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let snapshot_path = self.config.read().await.snapshot_path.clone();
+		let pair_snapshots = btrfs
+			.list_pair(&snapshot_path, pair_id)
+			.await
+			.context("failed to list snapshots")
+			.to_dbus_err()?;
+		if pair_snapshots
+			.iter()
+			.any(|snapshot| snapshot.pair.map(|pair| pair.kind) == Some(PairKind::Post))
+		{
+			return Err(anyhow!("snapshot pair {} has already ended", pair_id)).to_dbus_err();
+		}
+		let pre_snapshot = pair_snapshots
+			.into_iter()
+			.find(|snapshot| snapshot.pair.map(|pair| pair.kind) == Some(PairKind::Pre))
+			.ok_or_else(|| Error::NotFound(format!("snapshot pair {} does not exist", pair_id)))?;
+		let pair = SnapshotPair {
+			id: pair_id,
+			kind: PairKind::Post,
+		};
+		let trigger = pre_snapshot
+			.provenance
+			.map_or(SnapshotTrigger::Manual, |provenance| provenance.trigger);
+		let new_snapshot = NewSnapshot {
+			name: pre_snapshot.name,
+			description: pre_snapshot.description,
+			subvolumes: Some(pre_snapshot.subvolumes),
+			pair: Some(pair),
+			provenance: Some(collect_provenance(trigger, connection, &hdr).await),
+			tags: pre_snapshot.tags,
+			other_filesystems: Some(pre_snapshot.other_filesystems),
+		};
+		let (_, path) = self
+			.create_snapshot_object_queued(
+				new_snapshot,
+				&ProgressReporter::default(),
+				&ctxt,
+				object_server,
+			)
+			.await?;
 		Ok(path)
 	}
 
-	async fn find_snapshot(&self, uuid: &str) -> fdo::Result<Optional<OwnedObjectPath>> {
+	async fn find_snapshot(
+		&self,
+		uuid: &str,
+	) -> zbus_pop_snapshot::Result<Optional<OwnedObjectPath>> {
 		let snapshots = self.snapshots.read().await;
 		let uuid = Uuid::parse_str(uuid)
 			.with_context(|| format!("failed to parse UUID '{uuid}'", uuid = uuid))
-			.to_fdo_err()?;
+			.to_dbus_err()?;
 		let snapshot = snapshots
 			.iter()
 			.find(|(k, _)| **k == uuid)
@@ -92,13 +674,139 @@ impl SnapshotService {
 		Ok(snapshot.into())
 	}
 
-	async fn reload_config(&self) -> fdo::Result<()> {
+	/// Lists the snapshots in the trash, in the order they were deleted.
+	async fn list_trash(&self) -> zbus_pop_snapshot::Result<Vec<TrashedSnapshot>> {
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let config = self.config.read().await;
+		let mut trash = btrfs
+			.list_trash(&config.trash_path)
+			.await
+			.context("failed to list trash")
+			.to_dbus_err()?;
+		trash.sort_by_key(|snapshot| snapshot.deleted_time);
+		let format_time = |time: OffsetDateTime| {
+			time.format(&Rfc3339)
+				.expect("failed to format time as RFC 3399")
+		};
+		Ok(trash
+			.into_iter()
+			.map(|snapshot| {
+				(
+					snapshot.uuid.to_string(),
+					snapshot.name.unwrap_or_default(),
+					snapshot.description.unwrap_or_default(),
+					format_time(snapshot.creation_time),
+					snapshot.deleted_time.map(format_time).unwrap_or_default(),
+					snapshot.subvolumes,
+				)
+			})
+			.collect())
+	}
+
+	/// Moves a snapshot out of the trash, returning its new path.
+	async fn undelete(
+		&self,
+		uuid: &str,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let uuid = Uuid::parse_str(uuid)
+			.with_context(|| format!("failed to parse UUID '{}'", uuid))
+			.to_dbus_err()?;
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self.action_queue.acquire(Some(timeout)).await?;
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let config = self.config.read().await;
+		let snapshot = btrfs
+			.undelete_snapshot(uuid, &config.snapshot_path, &config.trash_path)
+			.await
+			.with_context(|| format!("failed to undelete snapshot {}", uuid))
+			.to_dbus_err()?;
+		let snapshot_object = SnapshotObject::new(
+			snapshot,
+			self.snapshots.clone(),
+			self.action_queue.clone(),
+			self.base.clone(),
+			self.config.clone(),
+		);
+		let path = create_new_snapshot(object_server, snapshot_object)
+			.await
+			.with_context(|| format!("failed to register snapshot '{uuid}'"))
+			.to_dbus_err()?;
+		self.snapshots.write().await.insert(uuid, path.clone());
+		drop(config);
+		let base_changed = update_base(&self.base, &self.config).await;
+		// To anyone watching, the snapshot has just reappeared.
+		object_manager::snapshot_added(object_server, &ctxt, &path)
+			.await
+			.to_dbus_err()?;
+		self.snapshots_changed(&ctxt)
+			.await
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?;
+		if base_changed {
+			report_base_changed(&ctxt, &self.base)
+				.await
+				.context("failed to emit PropertiesChanged signal")
+				.to_dbus_err()?;
+		}
+		Self::snapshot_created(&ctxt, &uuid.to_string())
+			.await
+			.context("failed to emit SnapshotCreated signal")
+			.to_dbus_err()?;
+		Ok(path)
+	}
+
+	/// Permanently deletes every snapshot in the trash.
+	async fn purge_trash(&self) -> zbus_pop_snapshot::Result<()> {
+		self.purge_all_trash(&ProgressReporter::default()).await?;
+		Ok(())
+	}
+
+	/// Like `PurgeTrash`, but returns a job tracking the purge
+	/// straight away, rather than waiting for it to finish.
+	async fn start_purge_trash(
+		&self,
+		#[zbus(connection)] connection: &Connection,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		let service = self.clone();
+		start_job(connection, "purge", move |progress| async move {
+			let purged = service.purge_all_trash(&progress).await?;
+			Ok(purged
+				.iter()
+				.map(Uuid::to_string)
+				.collect::<Vec<_>>()
+				.join(" "))
+		})
+		.await
+		.context("failed to start job")
+		.to_dbus_err()
+	}
+
+	async fn reload_config(
+		&self,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<()> {
 		info!("ReloadConfig called, reloading config");
-		let _lock = self.action_lock.lock().await;
+		let _lock = self.action_queue.acquire(None).await?;
 		crate::reload_config(self.config.clone())
 			.await
 			.context("failed to reload config")
-			.to_fdo_err()
+			.to_dbus_err()?;
+		// The root subvolume or the snapshot path may have changed.
+		if update_base(&self.base, &self.config).await {
+			report_base_changed(&ctxt, &self.base)
+				.await
+				.context("failed to emit PropertiesChanged signal")
+				.to_dbus_err()?;
+		}
+		Ok(())
 	}
 
 	#[dbus_interface(signal)]
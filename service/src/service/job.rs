@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::{
+	snapshot::progress::{Progress, ProgressReporter},
+	util::{dbus_error, ToDBusError},
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+	future::Future,
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Duration,
+};
+use tokio::sync::watch;
+use zbus::{dbus_interface, zvariant::OwnedObjectPath, Connection, DBusError, SignalContext};
+use zbus_pop_snapshot::Error;
+
+static JOB_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+/// How long a finished job stays on the bus, so that clients which
+/// missed the `Finished` signal can still read its result.
+const JOB_LINGER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+	/// Waiting for other operations to finish first.
+	Queued,
+	Running,
+	Succeeded,
+	Failed,
+	Cancelled,
+}
+
+impl JobState {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Queued => "queued",
+			Self::Running => "running",
+			Self::Succeeded => "succeeded",
+			Self::Failed => "failed",
+			Self::Cancelled => "cancelled",
+		}
+	}
+
+	pub fn is_finished(self) -> bool {
+		matches!(self, Self::Succeeded | Self::Failed | Self::Cancelled)
+	}
+}
+
+/// A long-running operation, running in the background.
+pub struct JobObject {
+	operation: &'static str,
+	state: JobState,
+	progress: Progress,
+	result: String,
+	error_name: String,
+	error: String,
+	cancel: watch::Sender<bool>,
+}
+
+impl JobObject {
+	fn new(operation: &'static str, cancel: watch::Sender<bool>) -> Self {
+		Self {
+			operation,
+			state: JobState::Running,
+			progress: Progress::default(),
+			result: String::new(),
+			error_name: String::new(),
+			error: String::new(),
+			cancel,
+		}
+	}
+}
+
+#[dbus_interface(name = "com.system76.PopSnapshot.Job")]
+impl JobObject {
+	/// What this job is doing: `create`, `restore`, `delete` or `purge`.
+	#[dbus_interface(property)]
+	async fn operation(&self) -> String {
+		self.operation.to_owned()
+	}
+
+	/// `queued`, `running`, `succeeded`, `failed` or `cancelled`.
+	#[dbus_interface(property)]
+	async fn state(&self) -> String {
+		self.state.as_str().to_owned()
+	}
+
+	/// How far along this job is, from 0 to 1.
+	#[dbus_interface(property)]
+	async fn progress(&self) -> f64 {
+		self.progress.fraction()
+	}
+
+	/// The subvolume currently being worked on, or an empty string.
+	#[dbus_interface(property)]
+	async fn current_subvolume(&self) -> String {
+		self.progress.current_subvolume.clone().unwrap_or_default()
+	}
+
+	/// Once the job has succeeded, the UUID of the snapshot it created,
+	/// the backup snapshot made by a restore, or the deleted snapshot.
+	/// For purges, the UUIDs of the purged snapshots, separated by spaces.
+	#[dbus_interface(property)]
+	async fn result(&self) -> String {
+		self.result.clone()
+	}
+
+	/// The D-Bus name of the error the job failed with, such as
+	/// `com.system76.PopSnapshot.Error.NoSpace`, if it did.
+	#[dbus_interface(property)]
+	async fn error_name(&self) -> String {
+		self.error_name.clone()
+	}
+
+	/// Why the job failed, if it did.
+	#[dbus_interface(property)]
+	async fn error(&self) -> String {
+		self.error.clone()
+	}
+
+	/// Asks for the job to stop as soon as it safely can, rolling back
+	/// anything it's done so far.
+	///
+	/// Restores can only be cancelled before they start replacing subvolumes,
+	/// and purges of the trash stop before the next snapshot.
+	/// Whether the job was cancelled is known once it's finished.
+	async fn cancel(&self) -> zbus_pop_snapshot::Result<()> {
+		if self.state.is_finished() {
+			return Err(anyhow!("the job has already finished")).to_dbus_err();
+		}
+		info!("Cancelling {} job", self.operation);
+		self.cancel.send_replace(true);
+		Ok(())
+	}
+
+	/// Emitted when the job is done, with its `Result`, `ErrorName` and `Error`.
+	#[dbus_interface(signal)]
+	async fn finished(
+		signal_ctxt: &SignalContext<'_>,
+		result: &str,
+		error_name: &str,
+		error: &str,
+	) -> zbus::Result<()>;
+}
+
+/// Starts running an operation in the background, returning the path of
+/// the job object that tracks it.
+///
+/// `run` is given the reporter to report the operation's progress through,
+/// and its future resolves to the job's result.
+pub(crate) async fn start_job<F, Fut>(
+	connection: &Connection,
+	operation: &'static str,
+	run: F,
+) -> Result<OwnedObjectPath>
+where
+	F: FnOnce(ProgressReporter) -> Fut,
+	Fut: Future<Output = Result<String>> + Send + 'static,
+{
+	let id = JOB_COUNTER.fetch_add(1, Ordering::SeqCst);
+	let path = OwnedObjectPath::try_from(format!("/com/system76/PopSnapshot/Job/{}", id))?;
+	let (cancel, cancelled) = watch::channel(false);
+	connection
+		.object_server()
+		.at(&path, JobObject::new(operation, cancel))
+		.await
+		.with_context(|| format!("failed to register job {:?}", path))?;
+	let (progress, receiver) = ProgressReporter::new(cancelled);
+	let job = run(progress);
+	let connection = connection.clone();
+	tokio::spawn({
+		let path = path.clone();
+		async move {
+			let watcher = tokio::spawn(watch_progress(connection.clone(), path.clone(), receiver));
+			let result = job.await;
+			// The reporter was dropped along with the job, so the watcher
+			// is done once it's caught up.
+			match watcher.await {
+				Ok(Ok(())) => {}
+				Ok(Err(err)) => warn!("Failed to report progress of {}: {err:#}", path.as_str()),
+				Err(err) => warn!("Failed to report progress of {}: {err}", path.as_str()),
+			}
+			if let Err(err) = finish_job(&connection, &path, result).await {
+				warn!("Failed to report result of {}: {err:#}", path.as_str());
+			}
+			tokio::time::sleep(JOB_LINGER).await;
+			if let Err(err) = connection
+				.object_server()
+				.remove::<JobObject, _>(&path)
+				.await
+			{
+				warn!("Failed to remove {}: {err}", path.as_str());
+			}
+		}
+	});
+	Ok(path)
+}
+
+/// Mirrors the progress reported by a job onto its object.
+async fn watch_progress(
+	connection: Connection,
+	path: OwnedObjectPath,
+	mut receiver: watch::Receiver<Progress>,
+) -> Result<()> {
+	let job = connection
+		.object_server()
+		.interface::<_, JobObject>(&path)
+		.await
+		.context("failed to get job object")?;
+	while receiver.changed().await.is_ok() {
+		let progress = receiver.borrow().clone();
+		let mut object = job.get_mut().await;
+		let steps_changed = (object.progress.completed, object.progress.total)
+			!= (progress.completed, progress.total);
+		let subvolume_changed = object.progress.current_subvolume != progress.current_subvolume;
+		let state = if progress.queued {
+			JobState::Queued
+		} else {
+			JobState::Running
+		};
+		let state_changed = object.state != state;
+		object.state = state;
+		object.progress = progress;
+		if state_changed {
+			object
+				.state_changed(job.signal_context())
+				.await
+				.context("failed to emit PropertiesChanged signal")?;
+		}
+		if steps_changed {
+			object
+				.progress_changed(job.signal_context())
+				.await
+				.context("failed to emit PropertiesChanged signal")?;
+		}
+		if subvolume_changed {
+			object
+				.current_subvolume_changed(job.signal_context())
+				.await
+				.context("failed to emit PropertiesChanged signal")?;
+		}
+	}
+	Ok(())
+}
+
+async fn finish_job(
+	connection: &Connection,
+	path: &OwnedObjectPath,
+	result: Result<String>,
+) -> Result<()> {
+	let job = connection
+		.object_server()
+		.interface::<_, JobObject>(path)
+		.await
+		.context("failed to get job object")?;
+	let mut object = job.get_mut().await;
+	match result {
+		Ok(result) => {
+			object.state = JobState::Succeeded;
+			object.result = result;
+		}
+		Err(err) => {
+			let err = dbus_error(&err);
+			let message = err.description().unwrap_or_default().to_owned();
+			// A job that was asked to cancel may still have failed for
+			// another reason before it noticed.
+			if matches!(err, Error::Cancelled(_)) {
+				info!("Job {} was cancelled", path.as_str());
+				object.state = JobState::Cancelled;
+			} else {
+				error!("Job {} failed: {}", path.as_str(), message);
+				object.state = JobState::Failed;
+			}
+			object.error_name = err.name().to_string();
+			object.error = message;
+		}
+	}
+	object
+		.state_changed(job.signal_context())
+		.await
+		.context("failed to emit PropertiesChanged signal")?;
+	JobObject::finished(
+		job.signal_context(),
+		&object.result,
+		&object.error_name,
+		&object.error,
+	)
+	.await
+	.context("failed to emit Finished signal")
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use super::snapshot::SnapshotObject;
+use crate::util::ToDBusError;
+use anyhow::{Context, Result};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use zbus::{
+	dbus_interface, fdo,
+	zvariant::{ObjectPath, OwnedObjectPath},
+	Interface, ObjectServer, SignalContext,
+};
+use zbus_pop_snapshot::{InterfaceProperties, ManagedObjects};
+
+/// Implements `org.freedesktop.DBus.ObjectManager` on the service root,
+/// so clients can get every snapshot and its properties in one call.
+pub struct ObjectManager {
+	snapshots: Arc<RwLock<HashMap<Uuid, OwnedObjectPath>>>,
+}
+
+impl ObjectManager {
+	pub fn new(snapshots: Arc<RwLock<HashMap<Uuid, OwnedObjectPath>>>) -> Self {
+		Self { snapshots }
+	}
+}
+
+#[dbus_interface(name = "org.freedesktop.DBus.ObjectManager")]
+impl ObjectManager {
+	async fn get_managed_objects(
+		&self,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> fdo::Result<ManagedObjects> {
+		let paths = self
+			.snapshots
+			.read()
+			.await
+			.values()
+			.cloned()
+			.collect::<Vec<_>>();
+		let mut objects = HashMap::with_capacity(paths.len());
+		for path in paths {
+			let interfaces = snapshot_interfaces(object_server, &path)
+				.await
+				.to_dbus_err()?;
+			objects.insert(path, interfaces);
+		}
+		Ok(objects)
+	}
+
+	#[dbus_interface(signal)]
+	async fn interfaces_added(
+		signal_ctxt: &SignalContext<'_>,
+		object_path: ObjectPath<'_>,
+		interfaces_and_properties: &InterfaceProperties,
+	) -> zbus::Result<()>;
+
+	#[dbus_interface(signal)]
+	async fn interfaces_removed(
+		signal_ctxt: &SignalContext<'_>,
+		object_path: ObjectPath<'_>,
+		interfaces: &[&str],
+	) -> zbus::Result<()>;
+}
+
+/// Gets all the properties of the snapshot object at `path`.
+async fn snapshot_interfaces(
+	object_server: &ObjectServer,
+	path: &OwnedObjectPath,
+) -> Result<InterfaceProperties> {
+	let snapshot = object_server
+		.interface::<_, SnapshotObject>(path)
+		.await
+		.with_context(|| format!("failed to get snapshot object {}", path.as_str()))?;
+	let properties = snapshot.get().await.get_all().await;
+	Ok(HashMap::from([(
+		<SnapshotObject as Interface>::name().to_string(),
+		properties,
+	)]))
+}
+
+/// Tells object manager clients that a snapshot object was added at `path`.
+///
+/// `ctxt` must be the signal context of the service root.
+pub(crate) async fn snapshot_added(
+	object_server: &ObjectServer,
+	ctxt: &SignalContext<'_>,
+	path: &OwnedObjectPath,
+) -> Result<()> {
+	let interfaces = snapshot_interfaces(object_server, path).await?;
+	ObjectManager::interfaces_added(ctxt, path.as_ref(), &interfaces)
+		.await
+		.context("failed to emit InterfacesAdded signal")
+}
+
+/// Tells object manager clients that the snapshot object at `path` was removed.
+///
+/// `ctxt` must be the signal context of the service root.
+pub(crate) async fn snapshot_removed(
+	ctxt: &SignalContext<'_>,
+	path: &OwnedObjectPath,
+) -> Result<()> {
+	ObjectManager::interfaces_removed(
+		ctxt,
+		path.as_ref(),
+		&[<SnapshotObject as Interface>::name().as_str()],
+	)
+	.await
+	.context("failed to emit InterfacesRemoved signal")
+}
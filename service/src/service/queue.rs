@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::snapshot::progress::{Cancelled, ProgressReporter};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{watch, Mutex, MutexGuard};
+use zbus_pop_snapshot::Error;
+
+/// Runs operations that change snapshots one at a time,
+/// in the order they were requested.
+#[derive(Debug, Clone)]
+pub struct ActionQueue {
+	lock: Arc<Mutex<()>>,
+	queued: Arc<watch::Sender<u32>>,
+}
+
+impl Default for ActionQueue {
+	fn default() -> Self {
+		Self {
+			lock: Arc::default(),
+			queued: Arc::new(watch::channel(0).0),
+		}
+	}
+}
+
+impl ActionQueue {
+	/// Waits for every operation queued before this one to finish.
+	///
+	/// If that takes longer than `timeout`, this fails with
+	/// [`Error::Busy`]. `None` waits for as long as it takes.
+	pub async fn acquire(&self, timeout: Option<Duration>) -> Result<MutexGuard<'_, ()>, Error> {
+		self.acquire_with_progress(timeout, &ProgressReporter::default())
+			.await
+	}
+
+	/// Like [`Self::acquire`], reporting the operation as queued
+	/// through `progress` while it waits, and giving up if it's cancelled.
+	pub async fn acquire_with_progress(
+		&self,
+		timeout: Option<Duration>,
+		progress: &ProgressReporter,
+	) -> Result<MutexGuard<'_, ()>, Error> {
+		if let Ok(guard) = self.lock.try_lock() {
+			return Ok(guard);
+		}
+		let _waiting = Waiting::new(&self.queued, progress);
+		let lock = async {
+			match timeout {
+				Some(timeout) => tokio::time::timeout(timeout, self.lock.lock()).await.ok(),
+				None => Some(self.lock.lock().await),
+			}
+		};
+		let guard = tokio::select! {
+			guard = lock => guard,
+			_ = progress.cancelled() => {
+				return Err(Error::Cancelled(Cancelled.to_string()));
+			}
+		};
+		guard.ok_or_else(|| {
+			Error::Busy(format!(
+				"pop-snapshot is busy, gave up waiting after {} seconds",
+				timeout.unwrap_or_default().as_secs()
+			))
+		})
+	}
+
+	/// How many operations are waiting for their turn.
+	pub fn queued(&self) -> u32 {
+		*self.queued.borrow()
+	}
+
+	/// Watches how many operations are waiting for their turn.
+	pub fn watch_queued(&self) -> watch::Receiver<u32> {
+		self.queued.subscribe()
+	}
+}
+
+/// Counts an operation as queued for as long as it's alive,
+/// even if the operation is dropped while waiting.
+struct Waiting<'a> {
+	queued: &'a watch::Sender<u32>,
+	progress: &'a ProgressReporter,
+}
+
+impl<'a> Waiting<'a> {
+	fn new(queued: &'a watch::Sender<u32>, progress: &'a ProgressReporter) -> Self {
+		queued.send_modify(|queued| *queued += 1);
+		progress.set_queued(true);
+		Self { queued, progress }
+	}
+}
+
+impl Drop for Waiting<'_> {
+	fn drop(&mut self) {
+		self.queued.send_modify(|queued| *queued -= 1);
+		self.progress.set_queued(false);
+	}
+}
@@ -1,68 +1,344 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use super::SnapshotService;
+use super::{
+	collect_provenance, job::start_job, object_manager, parse_operation_options,
+	queue::ActionQueue, SnapshotService,
+};
 use crate::{
 	config::Config,
 	create_new_snapshot,
-	snapshot::{metadata::SnapshotMetadata, MountedBtrfs},
-	util::ToFdoError,
+	snapshot::{
+		base::SystemBase,
+		list::BROKEN_DIR,
+		metadata::{normalize_tags, SnapshotMetadata},
+		progress::ProgressReporter,
+		provenance::{Provenance, SnapshotTrigger},
+		MountedBtrfs,
+	},
+	util::{ensure_root, ToDBusError},
 };
 use anyhow::{anyhow, Context, Result};
 use std::{collections::HashMap, sync::Arc};
 use time::format_description::well_known::Rfc3339;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{RwLock, RwLockReadGuard};
 use uuid::Uuid;
 use zbus::{
-	dbus_interface, fdo, zvariant::OwnedObjectPath, Connection, MessageHeader, ObjectServer,
-	SignalContext,
+	dbus_interface, fdo,
+	zvariant::{OwnedObjectPath, OwnedValue},
+	Connection, Interface, MessageHeader, ObjectServer, SignalContext,
 };
+use zbus_pop_snapshot::Error;
 
 pub struct SnapshotObject {
-	metadata: SnapshotMetadata,
+	uuid: Uuid,
+	/// Behind a lock of its own, so that methods waiting in the action queue
+	/// can change it afterwards without holding the interface's write lock,
+	/// which would stop clients from reading any properties in the meantime.
+	state: RwLock<SnapshotState>,
 	snapshots: Arc<RwLock<HashMap<Uuid, OwnedObjectPath>>>,
-	action_lock: Arc<Mutex<()>>,
+	action_queue: ActionQueue,
+	base: Arc<RwLock<SystemBase>>,
 	config: Arc<RwLock<Config>>,
 }
 
+struct SnapshotState {
+	metadata: SnapshotMetadata,
+	/// Why the metadata file couldn't be read, if it couldn't.
+	///
+	/// The metadata of a broken snapshot is reconstructed from
+	/// its subvolumes, so it isn't trustworthy enough to act on.
+	broken: Option<String>,
+	/// Whether the broken metadata file was moved into [`BROKEN_DIR`].
+	quarantined: bool,
+}
+
+impl SnapshotState {
+	fn ensure_not_broken(&self) -> Result<()> {
+		match &self.broken {
+			Some(error) => Err(anyhow!(
+				"the metadata of snapshot {} is broken ({}), repair it first",
+				self.metadata.uuid,
+				error
+			)),
+			None => Ok(()),
+		}
+	}
+
+	fn ensure_not_protected(&self) -> zbus_pop_snapshot::Result<()> {
+		if self.metadata.protected {
+			return Err(Error::PermissionDenied(format!(
+				"snapshot {} is protected, unprotect it or force deletion",
+				self.metadata.uuid
+			)));
+		}
+		Ok(())
+	}
+}
+
 impl SnapshotObject {
 	pub(crate) fn new(
 		metadata: SnapshotMetadata,
 		snapshots: Arc<RwLock<HashMap<Uuid, OwnedObjectPath>>>,
-		action_lock: Arc<Mutex<()>>,
+		action_queue: ActionQueue,
+		base: Arc<RwLock<SystemBase>>,
 		config: Arc<RwLock<Config>>,
 	) -> Self {
 		Self {
-			metadata,
+			uuid: metadata.uuid,
+			state: RwLock::new(SnapshotState {
+				metadata,
+				broken: None,
+				quarantined: false,
+			}),
 			snapshots,
-			action_lock,
+			action_queue,
+			base,
 			config,
 		}
 	}
+
+	/// Marks this snapshot as having unreadable metadata,
+	/// which is in [`BROKEN_DIR`] if `quarantined` is set.
+	pub(crate) fn with_broken_metadata(mut self, error: String, quarantined: bool) -> Self {
+		let state = self.state.get_mut();
+		state.broken = Some(error);
+		state.quarantined = quarantined;
+		self
+	}
 }
 
 impl SnapshotObject {
-	async fn update_metadata_file(&self) -> Result<()> {
-		let btrfs = MountedBtrfs::new().await.context("failed to mount btrfs")?;
+	pub(crate) fn snapshot_uuid(&self) -> Uuid {
+		self.uuid
+	}
+
+	async fn metadata(&self) -> RwLockReadGuard<'_, SnapshotMetadata> {
+		RwLockReadGuard::map(self.state.read().await, |state| &state.metadata)
+	}
+
+	/// Changes the metadata and writes it out, undoing the change if it
+	/// can't be written, so that clients never see changes that weren't saved.
+	///
+	/// Property setters emit `PropertiesChanged` once this succeeds.
+	async fn change_metadata(&mut self, change: impl FnOnce(&mut SnapshotMetadata)) -> Result<()> {
+		let state = self.state.get_mut();
+		state.ensure_not_broken()?;
+		let old_metadata = state.metadata.clone();
+		change(&mut state.metadata);
+		if let Err(err) = update_metadata_file(&self.config, &state.metadata).await {
+			state.metadata = old_metadata;
+			return Err(err).context("failed to update metadata file");
+		}
+		Ok(())
+	}
+
+	/// Emits `PropertiesChanged` for every property, for when the
+	/// whole of the metadata has been replaced.
+	async fn all_properties_changed(&self, ctxt: &SignalContext<'_>) -> zbus::Result<()> {
+		let properties = self.get_all().await;
+		let changed = properties
+			.iter()
+			.map(|(name, value)| (name.as_str(), &**value))
+			.collect::<HashMap<_, _>>();
+		fdo::Properties::properties_changed(ctxt, <Self as Interface>::name(), &changed, &[]).await
+	}
+
+	/// Moves this snapshot to the trash, unless it's protected
+	/// and `force` isn't set.
+	async fn delete_snapshot_object(
+		&self,
+		connection: &Connection,
+		path: &OwnedObjectPath,
+		trigger: SnapshotTrigger,
+		force: bool,
+		object_server: &ObjectServer,
+		progress: &ProgressReporter,
+	) -> zbus_pop_snapshot::Result<()> {
+		let timeout = self.config.read().await.queue_timeout();
+		let lock = self
+			.action_queue
+			.acquire_with_progress(Some(timeout), progress)
+			.await?;
 		let config = self.config.read().await;
-		let metadata_path = btrfs
-			.path()
-			.join(&config.snapshot_path)
-			.join(self.metadata.uuid.to_string())
-			.with_extension("snapshot.json");
-		tokio::fs::write(
-			&metadata_path,
-			serde_json::to_string_pretty(&self.metadata)?,
-		)
-		.await
-		.with_context(|| {
-			format!(
-				"failed to write updated metadata to file {}",
-				metadata_path.display()
+		// A copy, so that clients can still read it while this goes on.
+		let (metadata, quarantined) = {
+			let state = self.state.read().await;
+			// It may have been protected while this was waiting in the queue.
+			if !force {
+				state.ensure_not_protected()?;
+			}
+			(state.metadata.clone(), state.quarantined)
+		};
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		btrfs
+			.delete_snapshot(
+				&metadata,
+				&config.snapshot_path,
+				&config.trash_path,
+				&config.hooks_path,
+				trigger,
+				progress,
 			)
-		})?;
+			.await
+			.context("failed to delete snapshot")
+			.to_dbus_err()?;
+		let snapshots_dir = btrfs.path().join(&config.snapshot_path);
+		let metadata_path = if quarantined {
+			metadata.path(&snapshots_dir.join(BROKEN_DIR))
+		} else {
+			metadata.path(&snapshots_dir)
+		};
+		tokio::fs::remove_file(&metadata_path)
+			.await
+			.context("failed to remove snapshot metadata")
+			.to_dbus_err()?;
+		object_server
+			.remove::<Self, _>(path)
+			.await
+			.context("failed to remove object")
+			.to_dbus_err()?;
+		self.snapshots.write().await.remove(&self.uuid);
+		drop(config);
+		let base_changed = super::update_base(&self.base, &self.config).await;
+		// Let the next operation start before telling clients about this one.
+		drop(lock);
+		let base_service = self
+			.get_base_service(connection)
+			.await
+			.context("failed to get base service signal context")
+			.to_dbus_err()?;
+		object_manager::snapshot_removed(&base_service, path)
+			.await
+			.to_dbus_err()?;
+		super::report_snapshots_changed(&base_service, &self.snapshots)
+			.await
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?;
+		if base_changed {
+			super::report_base_changed(&base_service, &self.base)
+				.await
+				.context("failed to emit PropertiesChanged signal")
+				.to_dbus_err()?;
+		}
+		SnapshotService::snapshot_deleted(&base_service, &self.uuid.to_string())
+			.await
+			.context("failed to emit SnapshotDeleted signal")
+			.to_dbus_err()?;
 		Ok(())
 	}
 
+	async fn set_protected(
+		&self,
+		protected: bool,
+		ctxt: &SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<()> {
+		let mut state = self.state.write().await;
+		state.ensure_not_broken().to_dbus_err()?;
+		if state.metadata.protected == protected {
+			return Ok(());
+		}
+		state.metadata.protected = protected;
+		if let Err(err) = update_metadata_file(&self.config, &state.metadata).await {
+			state.metadata.protected = !protected;
+			return Err(err)
+				.context("failed to update metadata file")
+				.to_dbus_err();
+		}
+		drop(state);
+		self.protected_changed(ctxt)
+			.await
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()
+	}
+
+	/// Restores this snapshot and registers the backup snapshot made
+	/// while restoring, returning its UUID.
+	async fn restore_snapshot_object(
+		&self,
+		connection: &Connection,
+		provenance: Provenance,
+		object_server: &ObjectServer,
+		progress: &ProgressReporter,
+	) -> zbus_pop_snapshot::Result<Uuid> {
+		let timeout = self.config.read().await.queue_timeout();
+		let lock = self
+			.action_queue
+			.acquire_with_progress(Some(timeout), progress)
+			.await?;
+		let config = self.config.read().await;
+		let metadata = self.metadata().await.clone();
+		let btrfs = MountedBtrfs::new()
+			.await
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		let new_snapshot = btrfs
+			.restore_snapshot(
+				&metadata,
+				&config.snapshot_path,
+				&config.hooks_path,
+				provenance,
+				config.nested_subvolumes,
+				progress,
+			)
+			.await
+			.context("failed to restore snapshot")
+			.to_dbus_err()?;
+		let new_snapshot_uuid = new_snapshot.uuid;
+		let new_snapshot_object = SnapshotObject::new(
+			new_snapshot,
+			self.snapshots.clone(),
+			self.action_queue.clone(),
+			self.base.clone(),
+			self.config.clone(),
+		);
+		let path = create_new_snapshot(object_server, new_snapshot_object)
+			.await
+			.context("failed to register backup snapshot")
+			.to_dbus_err()?;
+		self.snapshots
+			.write()
+			.await
+			.insert(new_snapshot_uuid, path.clone());
+		drop(config);
+		let base_changed = super::update_base(&self.base, &self.config).await;
+		// Let the next operation start before telling clients about this one.
+		drop(lock);
+		let base_service = self
+			.get_base_service(connection)
+			.await
+			.context("failed to get base service signal context")
+			.to_dbus_err()?;
+		object_manager::snapshot_added(object_server, &base_service, &path)
+			.await
+			.to_dbus_err()?;
+		super::report_snapshots_changed(&base_service, &self.snapshots)
+			.await
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?;
+		if base_changed {
+			super::report_base_changed(&base_service, &self.base)
+				.await
+				.context("failed to emit PropertiesChanged signal")
+				.to_dbus_err()?;
+		}
+		SnapshotService::snapshot_restored(
+			&base_service,
+			&self.uuid.to_string(),
+			&new_snapshot_uuid.to_string(),
+		)
+		.await
+		.context("failed to emit SnapshotRestored signal")
+		.to_dbus_err()?;
+		SnapshotService::snapshot_created(&base_service, &new_snapshot_uuid.to_string())
+			.await
+			.context("failed to emit SnapshotCreated signal")
+			.to_dbus_err()?;
+		Ok(new_snapshot_uuid)
+	}
+
 	async fn get_base_service(&self, conn: &Connection) -> zbus::Result<SignalContext<'_>> {
 		let path = OwnedObjectPath::try_from("/com/system76/PopSnapshot")?;
 		SignalContext::new(conn, path)
@@ -73,7 +349,8 @@ impl SnapshotObject {
 impl SnapshotObject {
 	#[dbus_interface(property)]
 	async fn creation_time(&self) -> String {
-		self.metadata
+		self.metadata()
+			.await
 			.creation_time
 			.format(&Rfc3339)
 			.expect("failed to format time as RFC 3399")
@@ -81,155 +358,580 @@ impl SnapshotObject {
 
 	#[dbus_interface(property)]
 	async fn name(&self) -> String {
-		self.metadata.name.clone().unwrap_or_default()
+		self.metadata().await.name.clone().unwrap_or_default()
 	}
 
 	#[dbus_interface(property)]
 	async fn set_name(&mut self, value: &str) -> fdo::Result<()> {
-		self.metadata.name = if value.trim().is_empty() {
-			None
-		} else {
-			Some(value.to_owned())
-		};
-		self.update_metadata_file()
-			.await
-			.context("failed to update metadata file")
-			.to_fdo_err()?;
+		self.change_metadata(|metadata| {
+			metadata.name = if value.trim().is_empty() {
+				None
+			} else {
+				Some(value.to_owned())
+			};
+		})
+		.await
+		.to_dbus_err()?;
 		Ok(())
 	}
 
 	#[dbus_interface(property)]
 	async fn description(&self) -> String {
-		self.metadata.description.clone().unwrap_or_default()
+		self.metadata()
+			.await
+			.description
+			.clone()
+			.unwrap_or_default()
 	}
 
 	#[dbus_interface(property)]
 	async fn set_description(&mut self, value: &str) -> fdo::Result<()> {
-		self.metadata.description = if value.trim().is_empty() {
-			None
-		} else {
-			Some(value.to_owned())
-		};
-		self.update_metadata_file()
+		self.change_metadata(|metadata| {
+			metadata.description = if value.trim().is_empty() {
+				None
+			} else {
+				Some(value.to_owned())
+			};
+		})
+		.await
+		.to_dbus_err()?;
+		Ok(())
+	}
+
+	#[dbus_interface(property)]
+	async fn tags(&self) -> Vec<String> {
+		self.metadata().await.tags.clone()
+	}
+
+	#[dbus_interface(property)]
+	async fn set_tags(&mut self, value: Vec<String>) -> fdo::Result<()> {
+		self.change_metadata(|metadata| metadata.tags = normalize_tags(value))
 			.await
-			.context("failed to update metadata file")
-			.to_fdo_err()?;
+			.to_dbus_err()?;
 		Ok(())
 	}
 
 	#[dbus_interface(property)]
 	async fn subvolumes(&self) -> Vec<String> {
-		self.metadata.subvolumes.clone()
+		self.metadata().await.subvolumes.clone()
+	}
+
+	/// What was done with nested subvolumes when this snapshot was taken:
+	/// `skip`, `recursive` or `carry-over`. Empty if unknown.
+	#[dbus_interface(property)]
+	async fn nested_subvolumes(&self) -> String {
+		self.metadata()
+			.await
+			.nested_subvolumes
+			.map(|policy| policy.as_str().to_owned())
+			.unwrap_or_default()
+	}
+
+	/// The filesystems this snapshot has subvolumes on, as tuples of
+	/// their UUID and the snapshotted subvolumes.
+	///
+	/// The root filesystem comes first; its UUID is empty if it wasn't known.
+	#[dbus_interface(property)]
+	async fn filesystems(&self) -> Vec<(String, Vec<String>)> {
+		let metadata = self.metadata().await;
+		std::iter::once((
+			metadata.filesystem.clone().unwrap_or_default(),
+			metadata.subvolumes.clone(),
+		))
+		.chain(
+			metadata
+				.other_filesystems
+				.iter()
+				.map(|filesystem| (filesystem.uuid.clone(), filesystem.subvolumes.clone())),
+		)
+		.collect()
 	}
 
 	#[dbus_interface(property)]
 	async fn uuid(&self) -> String {
-		self.metadata.uuid.to_string()
+		self.uuid.to_string()
 	}
 
-	async fn restore(
+	#[dbus_interface(property)]
+	async fn pair_id(&self) -> String {
+		self.metadata()
+			.await
+			.pair
+			.map(|pair| pair.id.to_string())
+			.unwrap_or_default()
+	}
+
+	#[dbus_interface(property)]
+	async fn pair_kind(&self) -> String {
+		self.metadata()
+			.await
+			.pair
+			.map(|pair| pair.kind.as_str().to_owned())
+			.unwrap_or_default()
+	}
+
+	/// The UUID of the snapshot the live system had last been restored from
+	/// when this snapshot was taken, or an empty string if none.
+	#[dbus_interface(property)]
+	async fn parent(&self) -> String {
+		self.metadata()
+			.await
+			.parent
+			.map(|parent| parent.to_string())
+			.unwrap_or_default()
+	}
+
+	/// For a backup taken while restoring, the UUID of the snapshot
+	/// that was restored in its place. Otherwise, an empty string.
+	#[dbus_interface(property)]
+	async fn replaced_by(&self) -> String {
+		self.metadata()
+			.await
+			.replaced_by
+			.map(|replaced_by| replaced_by.to_string())
+			.unwrap_or_default()
+	}
+
+	/// What triggered this snapshot, or an empty string if unknown.
+	#[dbus_interface(property)]
+	async fn trigger(&self) -> String {
+		self.metadata()
+			.await
+			.provenance
+			.as_ref()
+			.map(|provenance| provenance.trigger.as_str().to_owned())
+			.unwrap_or_default()
+	}
+
+	/// The user ID of the process that requested this snapshot,
+	/// or `u32::MAX` (`(uid_t)-1`) if unknown.
+	#[dbus_interface(property)]
+	async fn sender_uid(&self) -> u32 {
+		self.metadata()
+			.await
+			.provenance
+			.as_ref()
+			.and_then(|provenance| provenance.sender_uid)
+			.unwrap_or(u32::MAX)
+	}
+
+	#[dbus_interface(property)]
+	async fn sender_process(&self) -> String {
+		self.metadata()
+			.await
+			.provenance
+			.as_ref()
+			.and_then(|provenance| provenance.sender_process.clone())
+			.unwrap_or_default()
+	}
+
+	#[dbus_interface(property)]
+	async fn hostname(&self) -> String {
+		self.metadata()
+			.await
+			.provenance
+			.as_ref()
+			.and_then(|provenance| provenance.hostname.clone())
+			.unwrap_or_default()
+	}
+
+	#[dbus_interface(property)]
+	async fn kernel_version(&self) -> String {
+		self.metadata()
+			.await
+			.provenance
+			.as_ref()
+			.and_then(|provenance| provenance.kernel.clone())
+			.unwrap_or_default()
+	}
+
+	#[dbus_interface(property)]
+	async fn os_version(&self) -> String {
+		self.metadata()
+			.await
+			.provenance
+			.as_ref()
+			.and_then(|provenance| provenance.os_version.clone())
+			.unwrap_or_default()
+	}
+
+	/// Whether this snapshot's metadata could be read: `ok` or `broken`.
+	#[dbus_interface(property)]
+	async fn state(&self) -> String {
+		match self.state.read().await.broken {
+			Some(_) => "broken".to_owned(),
+			None => "ok".to_owned(),
+		}
+	}
+
+	/// Why this snapshot's metadata couldn't be read, if it's broken.
+	#[dbus_interface(property)]
+	async fn metadata_error(&self) -> String {
+		self.state.read().await.broken.clone().unwrap_or_default()
+	}
+
+	/// Whether this snapshot is protected from deletion.
+	#[dbus_interface(property)]
+	async fn protected(&self) -> bool {
+		self.metadata().await.protected
+	}
+
+	/// Protects this snapshot from deletion.
+	async fn protect(
+		&self,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<()> {
+		self.set_protected(true, &ctxt).await
+	}
+
+	/// Allows this snapshot to be deleted again.
+	/// Only root may do this.
+	async fn unprotect(
 		&self,
 		#[zbus(connection)] connection: &Connection,
-		#[zbus(object_server)] object_server: &ObjectServer,
-	) -> fdo::Result<()> {
-		let _lock = match self.action_lock.try_lock() {
-			Ok(lock) => lock,
-			Err(_) => return Err(anyhow!("pop-snapshot is busy")).to_fdo_err(),
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<()> {
+		ensure_root(connection, &hdr, "unprotect snapshots")
+			.await
+			.to_dbus_err()?;
+		self.set_protected(false, &ctxt).await
+	}
+
+	/// The writable clones made of this snapshot,
+	/// as tuples of their ID, name and top-level subvolumes.
+	#[dbus_interface(property)]
+	async fn clones(&self) -> Vec<(String, String, Vec<String>)> {
+		self.metadata()
+			.await
+			.clones
+			.iter()
+			.map(|clone| {
+				(
+					clone.id.to_string(),
+					clone.name.clone(),
+					clone.subvolumes.clone(),
+				)
+			})
+			.collect()
+	}
+
+	/// Creates writable copies of this snapshot's subvolumes as new
+	/// top-level subvolumes, returning the ID of the clone.
+	///
+	/// Clones aren't included in new snapshots by default.
+	/// While this snapshot has clones, it isn't purged from the trash.
+	#[dbus_interface(name = "Clone")]
+	async fn clone_snapshot(
+		&self,
+		name: String,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<String> {
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self.action_queue.acquire(Some(timeout)).await?;
+		// Nothing else can change the metadata until the lock is dropped,
+		// and keeping a copy lets clients read it in the meantime.
+		let metadata = {
+			let state = self.state.read().await;
+			state.ensure_not_broken().to_dbus_err()?;
+			state.metadata.clone()
 		};
-		let config = self.config.read().await;
 		let btrfs = MountedBtrfs::new()
 			.await
 			.context("failed to mount btrfs")
-			.to_fdo_err()?;
-		let new_snapshot = btrfs
-			.restore_snapshot(&self.metadata, &config.snapshot_path)
+			.to_dbus_err()?;
+		let clone = {
+			let config = self.config.read().await;
+			btrfs
+				.clone_snapshot(&metadata, &config.snapshot_path, name)
+				.await
+				.context("failed to clone snapshot")
+				.to_dbus_err()?
+		};
+		let id = clone.id;
+		let mut state = self.state.write().await;
+		state.metadata.clones.push(clone);
+		if let Err(err) = update_metadata_file(&self.config, &state.metadata).await {
+			// Don't leave behind clones that nothing knows about.
+			let clone = state.metadata.clones.pop().expect("clone was just added");
+			drop(state);
+			if let Err(err) = btrfs.delete_clone(&clone).await {
+				warn!("Failed to delete untracked clone {id}: {err:#}");
+			}
+			return Err(err)
+				.context("failed to update metadata file")
+				.to_dbus_err();
+		}
+		drop(state);
+		self.clones_changed(&ctxt)
 			.await
-			.context("failed to restore snapshot")
-			.to_fdo_err()?;
-		let new_snapshot_uuid = new_snapshot.uuid;
-		let new_snapshot_object = SnapshotObject::new(
-			new_snapshot,
-			self.snapshots.clone(),
-			self.action_lock.clone(),
-			self.config.clone(),
-		);
-		let path = create_new_snapshot(object_server, new_snapshot_object)
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?;
+		Ok(id.to_string())
+	}
+
+	/// Deletes the subvolumes of a clone of this snapshot.
+	async fn delete_clone(
+		&self,
+		id: &str,
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<()> {
+		let id = Uuid::parse_str(id)
+			.with_context(|| format!("failed to parse clone ID '{}'", id))
+			.to_dbus_err()?;
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self.action_queue.acquire(Some(timeout)).await?;
+		let clone = {
+			let state = self.state.read().await;
+			state.ensure_not_broken().to_dbus_err()?;
+			state
+				.metadata
+				.clones
+				.iter()
+				.find(|clone| clone.id == id)
+				.cloned()
+				.ok_or_else(|| {
+					Error::NotFound(format!("snapshot {} has no clone {}", self.uuid, id))
+				})?
+		};
+		let btrfs = MountedBtrfs::new()
 			.await
-			.context("failed to register backup snapshot")
-			.to_fdo_err()?;
-		self.snapshots.write().await.insert(new_snapshot_uuid, path);
-		let base_service = self
-			.get_base_service(connection)
+			.context("failed to mount btrfs")
+			.to_dbus_err()?;
+		btrfs
+			.delete_clone(&clone)
 			.await
-			.context("failed to get base service signal context")
-			.to_fdo_err()?;
-		SnapshotService::snapshot_restored(
-			&base_service,
-			&self.metadata.uuid.to_string(),
-			&new_snapshot_uuid.to_string(),
-		)
-		.await
-		.context("failed to emit SnapshotRestored signal")
-		.to_fdo_err()?;
-		SnapshotService::snapshot_created(&base_service, &new_snapshot_uuid.to_string())
+			.context("failed to delete clone")
+			.to_dbus_err()?;
+		{
+			let mut state = self.state.write().await;
+			state.metadata.clones.retain(|clone| clone.id != id);
+			update_metadata_file(&self.config, &state.metadata)
+				.await
+				.context("failed to update metadata file")
+				.to_dbus_err()?;
+		}
+		Ok(self
+			.clones_changed(&ctxt)
 			.await
-			.context("failed to emit SnapshotCreated signal")
-			.to_fdo_err()?;
-		Ok(())
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?)
 	}
 
-	async fn delete(
+	/// Rebuilds the metadata of a broken snapshot from its subvolumes.
+	async fn repair_metadata(
 		&self,
-		#[zbus(connection)] connection: &Connection,
-		#[zbus(header)] hdr: MessageHeader<'_>,
-		#[zbus(object_server)] object_server: &ObjectServer,
-	) -> fdo::Result<()> {
-		let _lock = match self.action_lock.try_lock() {
-			Ok(lock) => lock,
-			Err(_) => return Err(anyhow!("pop-snapshot is busy")).to_fdo_err(),
+		#[zbus(signal_context)] ctxt: SignalContext<'_>,
+	) -> zbus_pop_snapshot::Result<()> {
+		let timeout = self.config.read().await.queue_timeout();
+		let _lock = self.action_queue.acquire(Some(timeout)).await?;
+		let quarantined = {
+			let state = self.state.read().await;
+			if state.broken.is_none() {
+				return Err(anyhow!(
+					"the metadata of snapshot {} isn't broken",
+					self.uuid
+				))
+				.to_dbus_err();
+			}
+			state.quarantined
 		};
 		let config = self.config.read().await;
 		let btrfs = MountedBtrfs::new()
 			.await
 			.context("failed to mount btrfs")
-			.to_fdo_err()?;
-		btrfs
-			.delete_snapshot(&self.metadata, &config.snapshot_path)
+			.to_dbus_err()?;
+		let metadata = btrfs
+			.reconstruct_metadata(self.uuid, &config.snapshot_path)
 			.await
-			.context("failed to delete snapshot")
-			.to_fdo_err()?;
-		let metadata_path = btrfs
-			.path()
-			.join(&config.snapshot_path)
-			.join(self.metadata.uuid.to_string())
-			.with_extension("snapshot.json");
-		tokio::fs::remove_file(&metadata_path)
+			.context("failed to reconstruct metadata")
+			.to_dbus_err()?;
+		let snapshots_dir = btrfs.path().join(&config.snapshot_path);
+		metadata
+			.write(&snapshots_dir)
 			.await
-			.context("failed to remove snapshot metadata")
-			.to_fdo_err()?;
-		let path = OwnedObjectPath::from(
-			hdr.path()
-				.context("failed to get own path")
-				.to_fdo_err()?
-				.context("invalid object path")
-				.to_fdo_err()?
-				.to_owned(),
-		);
-		object_server
-			.remove::<Self, _>(&path)
-			.await
-			.context("failed to remove object")
-			.to_fdo_err()?;
-		self.snapshots.write().await.remove(&self.metadata.uuid);
-		let base_service = self
-			.get_base_service(connection)
-			.await
-			.context("failed to get base service signal context")
-			.to_fdo_err()?;
-		SnapshotService::snapshot_deleted(&base_service, &self.metadata.uuid.to_string())
+			.context("failed to write repaired metadata")
+			.to_dbus_err()?;
+		// Otherwise, the broken file was just overwritten. If the broken copy
+		// stayed around, it would be listed alongside the repaired one, and
+		// this snapshot would still be broken as far as deleting it goes.
+		if quarantined {
+			let broken_path = metadata.path(&snapshots_dir.join(BROKEN_DIR));
+			if let Err(err) = tokio::fs::remove_file(&broken_path).await {
+				if let Err(err) = tokio::fs::remove_file(metadata.path(&snapshots_dir)).await {
+					warn!("Failed to remove repaired metadata: {err}");
+				}
+				return Err(err)
+					.with_context(|| {
+						format!("failed to remove broken metadata {}", broken_path.display())
+					})
+					.to_dbus_err();
+			}
+		}
+		info!("Repaired the metadata of snapshot {}", metadata.uuid);
+		*self.state.write().await = SnapshotState {
+			metadata,
+			broken: None,
+			quarantined: false,
+		};
+		drop(config);
+		self.all_properties_changed(&ctxt)
 			.await
-			.context("failed to emit SnapshotDeleted signal")
-			.to_fdo_err()?;
+			.context("failed to emit PropertiesChanged signal")
+			.to_dbus_err()?;
+		Ok(())
+	}
+
+	async fn restore(
+		&self,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<()> {
+		self.state.read().await.ensure_not_broken().to_dbus_err()?;
+		let provenance = collect_provenance(SnapshotTrigger::Manual, connection, &hdr).await;
+		self.restore_snapshot_object(
+			connection,
+			provenance,
+			object_server,
+			&ProgressReporter::default(),
+		)
+		.await?;
 		Ok(())
 	}
+
+	/// Like `Restore`, but returns a job tracking the restore
+	/// straight away, rather than waiting for it to finish.
+	///
+	/// The only option is `trigger`, as for `CreateSnapshotWithOptions`,
+	/// which is passed to the hooks.
+	async fn start_restore(
+		&self,
+		options: HashMap<String, OwnedValue>,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		self.state.read().await.ensure_not_broken().to_dbus_err()?;
+		let trigger = parse_operation_options(options)?;
+		let path = own_path(&hdr).to_dbus_err()?;
+		let provenance = collect_provenance(trigger, connection, &hdr).await;
+		let job_connection = connection.clone();
+		start_job(connection, "restore", move |progress| async move {
+			let object_server = job_connection.object_server();
+			let snapshot = object_server
+				.interface::<_, SnapshotObject>(&path)
+				.await
+				.context("failed to get snapshot object")?;
+			let snapshot = snapshot.get().await;
+			let backup_uuid = snapshot
+				.restore_snapshot_object(&job_connection, provenance, &object_server, &progress)
+				.await?;
+			Ok(backup_uuid.to_string())
+		})
+		.await
+		.context("failed to start job")
+		.to_dbus_err()
+	}
+
+	/// Moves this snapshot to the trash, unless it's protected.
+	async fn delete(
+		&self,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<()> {
+		self.state.read().await.ensure_not_protected()?;
+		let path = own_path(&hdr).to_dbus_err()?;
+		self.delete_snapshot_object(
+			connection,
+			&path,
+			SnapshotTrigger::Manual,
+			false,
+			object_server,
+			&ProgressReporter::default(),
+		)
+		.await
+	}
+
+	/// Like `Delete`, but returns a job tracking the deletion
+	/// straight away, rather than waiting for it to finish.
+	///
+	/// The only option is `trigger`, as for `CreateSnapshotWithOptions`,
+	/// which is passed to the hooks.
+	async fn start_delete(
+		&self,
+		options: HashMap<String, OwnedValue>,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+	) -> zbus_pop_snapshot::Result<OwnedObjectPath> {
+		self.state.read().await.ensure_not_protected()?;
+		let trigger = parse_operation_options(options)?;
+		let path = own_path(&hdr).to_dbus_err()?;
+		let job_connection = connection.clone();
+		start_job(connection, "delete", move |progress| async move {
+			let object_server = job_connection.object_server();
+			let snapshot = object_server
+				.interface::<_, SnapshotObject>(&path)
+				.await
+				.context("failed to get snapshot object")?;
+			let snapshot = snapshot.get().await;
+			snapshot
+				.delete_snapshot_object(
+					&job_connection,
+					&path,
+					trigger,
+					false,
+					&object_server,
+					&progress,
+				)
+				.await?;
+			Ok(snapshot.uuid.to_string())
+		})
+		.await
+		.context("failed to start job")
+		.to_dbus_err()
+	}
+
+	/// Moves this snapshot to the trash, even if it's protected.
+	/// Only root may do this.
+	async fn force_delete(
+		&self,
+		#[zbus(connection)] connection: &Connection,
+		#[zbus(header)] hdr: MessageHeader<'_>,
+		#[zbus(object_server)] object_server: &ObjectServer,
+	) -> zbus_pop_snapshot::Result<()> {
+		ensure_root(connection, &hdr, "force deletion of snapshots")
+			.await
+			.to_dbus_err()?;
+		let path = own_path(&hdr).to_dbus_err()?;
+		self.delete_snapshot_object(
+			connection,
+			&path,
+			SnapshotTrigger::Manual,
+			true,
+			object_server,
+			&ProgressReporter::default(),
+		)
+		.await
+	}
+}
+
+/// Writes out the changed metadata of a snapshot.
+async fn update_metadata_file(config: &RwLock<Config>, metadata: &SnapshotMetadata) -> Result<()> {
+	let btrfs = MountedBtrfs::new().await.context("failed to mount btrfs")?;
+	let config = config.read().await;
+	metadata
+		.write(&btrfs.path().join(&config.snapshot_path))
+		.await
+		.context("failed to write updated metadata")
+}
+
+/// Finds the path of the object a D-Bus message was sent to.
+fn own_path(hdr: &MessageHeader<'_>) -> Result<OwnedObjectPath> {
+	Ok(OwnedObjectPath::from(
+		hdr.path()
+			.context("failed to get own path")?
+			.context("invalid object path")?
+			.to_owned(),
+	))
 }
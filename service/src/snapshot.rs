@@ -1,18 +1,28 @@
 // SPDX-License-Identifier: MPL-2.0
 
+pub mod base;
+pub mod clone;
 pub mod create;
 pub mod delete;
+pub mod journal;
 pub mod list;
 pub mod metadata;
 pub mod mount;
+pub mod nested;
+pub mod progress;
+pub mod provenance;
+pub mod quiesce;
 pub mod restore;
+pub mod select;
+pub mod trash;
 
 use std::path::Path;
 use sys_mount::{Mount, UnmountDrop};
 use tempfile::TempDir;
 
 pub struct MountedBtrfs {
-	_mount: UnmountDrop<Mount>,
+	/// Only `None` in tests, which work on a plain directory.
+	_mount: Option<UnmountDrop<Mount>>,
 	tempdir: TempDir,
 }
 
@@ -20,4 +30,14 @@ impl MountedBtrfs {
 	pub fn path(&self) -> &Path {
 		self.tempdir.path()
 	}
+
+	/// Treats a plain directory as the base subvolume, for tests
+	/// of what doesn't need an actual btrfs filesystem.
+	#[cfg(test)]
+	pub(crate) fn unmounted(tempdir: TempDir) -> Self {
+		Self {
+			_mount: None,
+			tempdir,
+		}
+	}
 }
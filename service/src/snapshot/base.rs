@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{journal, MountedBtrfs};
+use anyhow::{Context, Result};
+use std::{collections::HashMap, path::Path};
+use uuid::Uuid;
+
+/// Which snapshots the system is based on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SystemBase {
+	/// The snapshot the running root subvolume was restored from, if any.
+	pub running: Option<Uuid>,
+	/// The snapshot the root subvolume that will be booted next
+	/// was restored from, if any.
+	pub next_boot: Option<Uuid>,
+	/// Whether a restore has been applied, but not yet booted into.
+	pub restore_pending: bool,
+}
+
+impl MountedBtrfs {
+	/// Works out which snapshots the running system, and the system
+	/// that will be booted next, were restored from.
+	///
+	/// A restored root subvolume is a snapshot of a snapshot's subvolume,
+	/// so its btrfs parent UUID points to where it came from. If that
+	/// snapshot can't be found, the restore journal is used instead.
+	pub async fn system_base(
+		&self,
+		snapshot_path: &Path,
+		root_subvolume: &str,
+	) -> Result<SystemBase> {
+		let snapshots_dir = self.path().join(snapshot_path);
+		let live_root = self.path().join(root_subvolume);
+		let (running, live, origins) = {
+			let snapshots_dir = snapshots_dir.clone();
+			tokio::task::spawn_blocking(move || -> Result<_> {
+				let running = libbtrfsutil::subvolume_info("/", None)
+					.context("failed to get info of the running root subvolume")?;
+				let live = libbtrfsutil::subvolume_info(&live_root, None).with_context(|| {
+					format!("failed to get info of subvolume {}", live_root.display())
+				})?;
+				Ok((running, live, snapshot_subvolume_origins(&snapshots_dir)))
+			})
+			.await??
+		};
+		let journal_base = journal::current_base(&snapshots_dir).await;
+		let origin_of = |parent_uuid: Option<Uuid>| {
+			parent_uuid.and_then(|parent_uuid| origins.get(&parent_uuid).copied())
+		};
+		let restore_pending = running.uuid() != live.uuid();
+		let next_boot = origin_of(live.parent_uuid()).or(journal_base);
+		// Without a pending restore, the running system is the one the journal
+		// refers to. Otherwise, it's whatever was restored before that.
+		let running =
+			origin_of(running.parent_uuid()).or(if restore_pending { None } else { journal_base });
+		Ok(SystemBase {
+			running,
+			next_boot,
+			restore_pending,
+		})
+	}
+}
+
+/// Maps the btrfs UUID of every subvolume in every snapshot
+/// to the UUID of the snapshot it's part of.
+///
+/// Anything that can't be read is skipped, as this is informational.
+fn snapshot_subvolume_origins(snapshots_dir: &Path) -> HashMap<Uuid, Uuid> {
+	let mut origins = HashMap::new();
+	let entries = match std::fs::read_dir(snapshots_dir) {
+		Ok(entries) => entries,
+		Err(err) => {
+			warn!("Failed to read {}: {err}", snapshots_dir.display());
+			return origins;
+		}
+	};
+	for entry in entries.flatten() {
+		let snapshot_uuid = match entry
+			.file_name()
+			.to_str()
+			.and_then(|name| Uuid::parse_str(name).ok())
+		{
+			Some(uuid) => uuid,
+			None => continue,
+		};
+		let subvolumes = match std::fs::read_dir(entry.path()) {
+			Ok(subvolumes) => subvolumes,
+			Err(_) => continue,
+		};
+		for subvolume in subvolumes.flatten() {
+			match libbtrfsutil::subvolume_info(subvolume.path(), None) {
+				Ok(info) => {
+					origins.insert(info.uuid(), snapshot_uuid);
+				}
+				Err(err) => debug!(
+					"Failed to get info of subvolume {}: {err}",
+					subvolume.path().display()
+				),
+			}
+		}
+	}
+	origins
+}
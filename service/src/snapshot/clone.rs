@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{metadata::SnapshotMetadata, MountedBtrfs};
+use anyhow::{anyhow, Context, Result};
+use libbtrfsutil::{CreateSnapshotFlags, DeleteSubvolumeFlags};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use time::OffsetDateTime;
+use uuid::Uuid;
+use zbus_pop_snapshot::Error;
+
+/// A set of writable subvolumes that were cloned from a snapshot.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct CloneRecord {
+	pub id: Uuid,
+	#[serde(default, skip_serializing_if = "String::is_empty")]
+	pub name: String,
+	#[serde(with = "time::serde::rfc3339")]
+	pub creation_time: OffsetDateTime,
+	/// The top-level subvolumes that were created.
+	pub subvolumes: Vec<String>,
+}
+
+impl MountedBtrfs {
+	/// Creates a writable copy of every subvolume in a snapshot, as new
+	/// top-level subvolumes named like `@root-clone-<short id>`.
+	///
+	/// Unlike restoring, this leaves the live subvolumes untouched.
+	/// Only subvolumes on the root filesystem are cloned.
+	pub async fn clone_snapshot(
+		&self,
+		snapshot: &SnapshotMetadata,
+		snapshot_path: &Path,
+		name: String,
+	) -> Result<CloneRecord> {
+		let snapshot_dir = self
+			.path()
+			.join(snapshot_path)
+			.join(snapshot.uuid.to_string());
+		if !snapshot_dir.exists() {
+			return Err(
+				Error::NotFound(format!("snapshot {} does not exist", snapshot.uuid)).into(),
+			);
+		}
+		let id = Uuid::new_v4();
+		let short_id = id.simple().to_string()[..8].to_owned();
+		let mut created = Vec::with_capacity(snapshot.subvolumes.len());
+		for subvolume in &snapshot.subvolumes {
+			let flattened = subvolume.replace('/', "__");
+			let clone_name = format!("{flattened}-clone-{short_id}");
+			let source = snapshot_dir.join(&flattened);
+			let destination = self.path().join(&clone_name);
+			info!("Cloning {} to {}", source.display(), destination.display());
+			let result = if destination.exists() {
+				Err(anyhow!("subvolume '{}' already exists", clone_name))
+			} else {
+				tokio::task::spawn_blocking(move || {
+					libbtrfsutil::create_snapshot(
+						&source,
+						&destination,
+						CreateSnapshotFlags::empty(),
+						None,
+					)
+				})
+				.await?
+				.with_context(|| format!("failed to clone subvolume '{}'", subvolume))
+			};
+			if let Err(err) = result {
+				self.delete_clone_subvolumes(&created).await;
+				return Err(err);
+			}
+			created.push(clone_name);
+		}
+		Ok(CloneRecord {
+			id,
+			name,
+			creation_time: OffsetDateTime::now_utc(),
+			subvolumes: created,
+		})
+	}
+
+	/// Deletes the subvolumes of a clone.
+	pub async fn delete_clone(&self, clone: &CloneRecord) -> Result<()> {
+		for subvolume in &clone.subvolumes {
+			let path = self.path().join(subvolume);
+			if !path.exists() {
+				warn!("Clone subvolume '{subvolume}' is already gone");
+				continue;
+			}
+			info!("Deleting clone subvolume '{subvolume}'");
+			// Clones are writable, so subvolumes may have been created within them.
+			tokio::task::spawn_blocking(move || {
+				libbtrfsutil::delete_subvolume(&path, DeleteSubvolumeFlags::RECURSIVE)
+			})
+			.await?
+			.with_context(|| format!("failed to delete subvolume '{}'", subvolume))?;
+		}
+		Ok(())
+	}
+
+	/// Deletes the subvolumes of a clone that failed partway through.
+	///
+	/// This is best-effort; any failures are logged, not returned.
+	async fn delete_clone_subvolumes(&self, subvolumes: &[String]) {
+		for subvolume in subvolumes.iter().rev() {
+			let path = self.path().join(subvolume);
+			info!("Deleting partially created clone subvolume '{subvolume}'");
+			let result = tokio::task::spawn_blocking(move || {
+				libbtrfsutil::delete_subvolume(&path, DeleteSubvolumeFlags::empty())
+			})
+			.await;
+			match result {
+				Ok(Ok(())) => {}
+				Ok(Err(err)) => warn!("Failed to delete subvolume '{subvolume}': {err}"),
+				Err(err) => warn!("Failed to delete subvolume '{subvolume}': {err}"),
+			}
+		}
+	}
+}
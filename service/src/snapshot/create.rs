@@ -1,50 +1,239 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use super::{metadata::SnapshotMetadata, MountedBtrfs};
-use crate::{config::Config, util::list_subvolumes_eligible_for_snapshotting};
+use super::{
+	journal,
+	metadata::{normalize_tags, FilesystemSubvolumes, SnapshotMetadata, SnapshotPair},
+	progress::{Cancelled, ProgressReporter},
+	provenance::Provenance,
+	quiesce, MountedBtrfs,
+};
+use crate::{
+	config::Config,
+	hooks::{self, HookContext, HookOperation, HookStage},
+	util::root_filesystem_uuid,
+};
 use anyhow::{Context, Result};
-use libbtrfsutil::CreateSnapshotFlags;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use libbtrfsutil::{CreateSnapshotFlags, DeleteSubvolumeFlags};
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+use tokio::{fs, sync::RwLock};
+
+/// The details of a snapshot that is about to be taken.
+#[derive(Debug, Default)]
+pub struct NewSnapshot {
+	pub name: Option<String>,
+	pub description: Option<String>,
+	/// The subvolumes to snapshot, as patterns or mountpoints,
+	/// or `None` to use the configured defaults.
+	pub subvolumes: Option<Vec<String>>,
+	pub pair: Option<SnapshotPair>,
+	pub provenance: Option<Provenance>,
+	pub tags: Vec<String>,
+	/// The subvolumes to snapshot on other filesystems,
+	/// or `None` to use the configured defaults.
+	pub other_filesystems: Option<Vec<FilesystemSubvolumes>>,
+}
 
 impl MountedBtrfs {
 	pub async fn create_snapshot(
 		&self,
-		name: impl Into<Option<String>>,
-		description: impl Into<Option<String>>,
-		subvolumes: impl Into<Option<Vec<String>>>,
+		new_snapshot: NewSnapshot,
 		config: Arc<RwLock<Config>>,
+		progress: &ProgressReporter,
 	) -> Result<SnapshotMetadata> {
 		let config = config.read().await;
-		let subvolumes_to_snapshot = match (subvolumes.into(), config.include_subvolumes.clone()) {
-			(Some(subvolumes), _) | (None, Some(subvolumes)) => subvolumes,
-			(None, None) => {
-				let path = self.path().to_path_buf();
-				let exclude_subvolumes = config.exclude_subvolumes.clone();
-				tokio::task::spawn_blocking(move || {
-					list_subvolumes_eligible_for_snapshotting(&path, &exclude_subvolumes)
-				})
-				.await?
-				.context("failed to get eligible subvolumes to snapshot")?
+		let NewSnapshot {
+			name,
+			description,
+			subvolumes,
+			pair,
+			provenance,
+			tags,
+			other_filesystems,
+		} = new_snapshot;
+		let subvolumes_to_snapshot = self
+			.resolve_subvolumes(subvolumes, &config)
+			.await
+			.context("failed to resolve subvolumes to snapshot")?;
+		let num_subvolumes = subvolumes_to_snapshot.len();
+		let mut snapshot = SnapshotMetadata::now(name, description, subvolumes_to_snapshot);
+		snapshot.pair = pair;
+		snapshot.provenance = provenance;
+		snapshot.tags = normalize_tags(tags);
+		snapshot.nested_subvolumes = Some(config.nested_subvolumes);
+		snapshot.filesystem = match root_filesystem_uuid().await {
+			Ok(uuid) => Some(uuid),
+			Err(err) => {
+				warn!("Failed to find the UUID of the root filesystem: {err:#}");
+				None
 			}
 		};
-		let num_subvolumes = subvolumes_to_snapshot.len();
-		let snapshot = SnapshotMetadata::now(name, description, subvolumes_to_snapshot);
+		snapshot.other_filesystems = other_filesystems.unwrap_or_else(|| {
+			config
+				.filesystems
+				.iter()
+				.map(|filesystem| FilesystemSubvolumes {
+					uuid: filesystem.uuid.clone(),
+					subvolumes: config
+						.nested_subvolumes
+						.filter(filesystem.subvolumes.clone()),
+				})
+				.collect()
+		});
+		// Mount everything up front, so a missing disk is noticed
+		// before anything is changed.
+		let other_mounts = MountedBtrfs::mount_other_filesystems(&snapshot).await?;
+		let snapshots_dir = self.path().join(&config.snapshot_path);
+		snapshot.parent = journal::current_base(&snapshots_dir).await;
+		let trigger = snapshot
+			.provenance
+			.as_ref()
+			.map_or("manual", |provenance| provenance.trigger.as_str());
 		info!(
 			"Creating snapshot '{}' with {num_subvolumes} subvolumes",
 			snapshot.uuid
 		);
-		let snapshot_dir = self
-			.path()
-			.join(&config.snapshot_path)
-			.join(snapshot.uuid.to_string());
+		progress.check_cancelled()?;
+		hooks::run_hooks(
+			&config.hooks_path,
+			HookStage::Pre,
+			&HookContext::new(HookOperation::Create, &snapshot, trigger),
+		)
+		.await?;
+		let snapshot_dir = snapshots_dir.join(snapshot.uuid.to_string());
 		if !snapshot_dir.is_dir() {
-			std::fs::create_dir_all(&snapshot_dir).context("failed to create snapshot dir")?;
+			if let Err(err) = std::fs::create_dir_all(&snapshot_dir) {
+				let err = anyhow::Error::from(err).context("failed to create snapshot dir");
+				run_post_hooks_after_failure(&config.hooks_path, &snapshot, trigger, &err).await;
+				return Err(err);
+			}
+		}
+
+		// Write provisional metadata first, so that if we're interrupted,
+		// the leftovers can be found and cleaned up later.
+		snapshot.incomplete = true;
+		if let Err(err) = snapshot.write(&snapshots_dir).await {
+			discard_incomplete_snapshot(&snapshot, &snapshots_dir, &[]).await;
+			let err = err.context("failed to write provisional snapshot metadata");
+			run_post_hooks_after_failure(&config.hooks_path, &snapshot, trigger, &err).await;
+			return Err(err);
+		}
+
+		// Quiesce applications, then flush everything to disk, so that all the
+		// subvolumes are captured in a consistent state.
+		let hooks = config.quiesce_hooks.as_slice();
+		let mut quiesce_records = Vec::with_capacity(hooks.len() * 2);
+		if let Err(err) = quiesce::freeze(hooks, &mut quiesce_records).await {
+			discard_incomplete_snapshot(&snapshot, &snapshots_dir, &[]).await;
+			let err = err.context("failed to quiesce applications");
+			run_post_hooks_after_failure(&config.hooks_path, &snapshot, trigger, &err).await;
+			return Err(err);
+		}
+		if config.sync_filesystem {
+			snapshot.synced = true;
+			for btrfs in std::iter::once(self).chain(&other_mounts) {
+				if let Err(err) = quiesce::syncfs(btrfs.path()).await {
+					warn!("Failed to sync filesystem before snapshotting: {err:#}");
+					snapshot.synced = false;
+				}
+			}
 		}
-		for subvolume in &snapshot.subvolumes {
+
+		progress.add_steps(
+			num_subvolumes
+				+ snapshot
+					.other_filesystems
+					.iter()
+					.map(|filesystem| filesystem.subvolumes.len())
+					.sum::<usize>(),
+		);
+		let mut created_subvolumes = Vec::with_capacity(num_subvolumes);
+		let mut result = self
+			.snapshot_subvolumes(
+				&snapshot.subvolumes,
+				&snapshot_dir,
+				&mut created_subvolumes,
+				progress,
+			)
+			.await;
+		for (filesystem, btrfs) in snapshot.other_filesystems.iter().zip(&other_mounts) {
+			if result.is_err() {
+				break;
+			}
+			let snapshot_dir = btrfs
+				.path()
+				.join(&config.snapshot_path)
+				.join(snapshot.uuid.to_string());
+			result = match fs::create_dir_all(&snapshot_dir).await {
+				Ok(()) => btrfs
+					.snapshot_subvolumes(
+						&filesystem.subvolumes,
+						&snapshot_dir,
+						&mut created_subvolumes,
+						progress,
+					)
+					.await
+					.with_context(|| format!("failed to snapshot filesystem {}", filesystem.uuid)),
+				Err(err) => Err(err).with_context(|| {
+					format!("failed to create directory {}", snapshot_dir.display())
+				}),
+			};
+		}
+		quiesce::thaw(hooks, &mut quiesce_records).await;
+		snapshot.quiesce = quiesce_records;
+		if let Err(err) = result {
+			error!(
+				"Failed to create snapshot '{}', rolling back",
+				snapshot.uuid
+			);
+			discard_incomplete_snapshot(&snapshot, &snapshots_dir, &created_subvolumes).await;
+			discard_other_snapshot_dirs(&snapshot, &other_mounts, &config.snapshot_path).await;
+			run_post_hooks_after_failure(&config.hooks_path, &snapshot, trigger, &err).await;
+			return Err(err);
+		}
+
+		snapshot.incomplete = false;
+		if let Err(err) = snapshot.write(&snapshots_dir).await {
+			error!(
+				"Failed to finalize snapshot '{}', rolling back",
+				snapshot.uuid
+			);
+			discard_incomplete_snapshot(&snapshot, &snapshots_dir, &created_subvolumes).await;
+			discard_other_snapshot_dirs(&snapshot, &other_mounts, &config.snapshot_path).await;
+			let err = err.context("failed to finalize snapshot metadata");
+			run_post_hooks_after_failure(&config.hooks_path, &snapshot, trigger, &err).await;
+			return Err(err);
+		}
+		hooks::run_hooks(
+			&config.hooks_path,
+			HookStage::Post,
+			&HookContext::new(HookOperation::Create, &snapshot, trigger),
+		)
+		.await?;
+
+		Ok(snapshot)
+	}
+
+	/// Takes a read-only snapshot of each subvolume into `snapshot_dir`,
+	/// adding the path of each created snapshot to `created` as it goes.
+	///
+	/// Stops before the next subvolume if the operation is cancelled.
+	async fn snapshot_subvolumes(
+		&self,
+		subvolumes: &[String],
+		snapshot_dir: &Path,
+		created: &mut Vec<PathBuf>,
+		progress: &ProgressReporter,
+	) -> Result<()> {
+		for subvolume in subvolumes {
+			progress.check_cancelled()?;
 			info!("Snapshotting {subvolume}");
+			progress.start_subvolume(subvolume);
 			let source = self.path().join(subvolume);
 			let destination = snapshot_dir.join(&subvolume.replace('/', "__"));
+			let created_path = destination.clone();
 			tokio::task::spawn_blocking(move || {
 				libbtrfsutil::create_snapshot(
 					&source,
@@ -55,25 +244,97 @@ impl MountedBtrfs {
 			})
 			.await?
 			.with_context(|| format!("failed to snapshot subvolume '{}'", subvolume))?;
+			created.push(created_path);
+			progress.complete_step();
 		}
+		Ok(())
+	}
+}
 
-		let snapshot_metadata_path = self
-			.path()
-			.join(&config.snapshot_path)
-			.join(snapshot.uuid.to_string())
-			.with_extension("snapshot.json");
-		tokio::fs::write(
-			&snapshot_metadata_path,
-			serde_json::to_string_pretty(&snapshot)?,
-		)
-		.await
-		.with_context(|| {
-			format!(
-				"failed to write snapshot metadata to '{}'",
-				snapshot_metadata_path.display()
-			)
-		})?;
+/// Runs the post-create hooks for a snapshot that failed after the
+/// pre-create hooks ran, so that they can undo whatever those did.
+async fn run_post_hooks_after_failure(
+	hooks_path: &Path,
+	snapshot: &SnapshotMetadata,
+	trigger: &str,
+	err: &anyhow::Error,
+) {
+	let mut hook_context = HookContext::new(HookOperation::Create, snapshot, trigger);
+	hook_context.failed = true;
+	hook_context.cancelled = err.chain().any(|cause| cause.is::<Cancelled>());
+	if let Err(hook_err) = hooks::run_hooks(hooks_path, HookStage::Post, &hook_context).await {
+		warn!("Failed to run post-create hooks: {hook_err:#}");
+	}
+}
 
-		Ok(snapshot)
+/// Removes everything that was created for a snapshot that failed
+/// partway through: the given subvolumes, the snapshot directory,
+/// and the provisional metadata file.
+///
+/// This is best-effort; any failures are logged, not returned,
+/// as we're usually already handling another error.
+pub(crate) async fn discard_incomplete_snapshot(
+	snapshot: &SnapshotMetadata,
+	snapshots_dir: &Path,
+	subvolumes: &[PathBuf],
+) {
+	for subvolume in subvolumes.iter().rev() {
+		info!(
+			"Deleting partially created subvolume {}",
+			subvolume.display()
+		);
+		let path = subvolume.clone();
+		let result = tokio::task::spawn_blocking(move || {
+			libbtrfsutil::delete_subvolume(&path, DeleteSubvolumeFlags::empty())
+		})
+		.await;
+		match result {
+			Ok(Ok(())) => {}
+			Ok(Err(err)) => warn!("Failed to delete subvolume {}: {err}", subvolume.display()),
+			Err(err) => warn!("Failed to delete subvolume {}: {err}", subvolume.display()),
+		}
+	}
+	let snapshot_dir = snapshots_dir.join(snapshot.uuid.to_string());
+	if snapshot_dir.exists() {
+		if let Err(err) = tokio::fs::remove_dir(&snapshot_dir).await {
+			warn!(
+				"Failed to remove snapshot directory {}: {err}",
+				snapshot_dir.display()
+			);
+		}
+	}
+	let metadata_path = snapshot.path(snapshots_dir);
+	if metadata_path.exists() {
+		if let Err(err) = tokio::fs::remove_file(&metadata_path).await {
+			warn!(
+				"Failed to remove provisional metadata {}: {err}",
+				metadata_path.display()
+			);
+		}
+	}
+}
+
+/// Removes the (by now empty) directories that were created on other
+/// filesystems for a snapshot that failed partway through.
+///
+/// This is best-effort; any failures are logged, not returned.
+pub(crate) async fn discard_other_snapshot_dirs(
+	snapshot: &SnapshotMetadata,
+	mounts: &[MountedBtrfs],
+	snapshot_path: &Path,
+) {
+	for btrfs in mounts {
+		let snapshot_dir = btrfs
+			.path()
+			.join(snapshot_path)
+			.join(snapshot.uuid.to_string());
+		if snapshot_dir.exists() {
+			if let Err(err) = fs::remove_dir(&snapshot_dir).await {
+				warn!(
+					"Failed to remove snapshot directory {}: {err}",
+					snapshot_dir.display()
+				);
+			}
+		}
 	}
 }
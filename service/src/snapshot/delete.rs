@@ -1,41 +1,45 @@
 // SPDX-License-Identifier: MPL-2.0
-use super::{metadata::SnapshotMetadata, MountedBtrfs};
-use anyhow::{anyhow, Context, Result};
-use libbtrfsutil::DeleteSubvolumeFlags;
+use super::{
+	metadata::SnapshotMetadata, progress::ProgressReporter, provenance::SnapshotTrigger,
+	MountedBtrfs,
+};
+use crate::hooks::{self, HookContext, HookOperation, HookStage};
+use anyhow::Result;
 use std::path::Path;
-use tokio::fs;
+use zbus_pop_snapshot::Error;
 
 impl MountedBtrfs {
+	/// Deletes a snapshot by moving it into the trash,
+	/// from which it can be undeleted until it's purged.
+	///
+	/// `trigger` is what asked for the deletion, as passed to the hooks.
 	pub async fn delete_snapshot(
 		&self,
 		snapshot: &SnapshotMetadata,
 		snapshot_path: &Path,
+		trash_path: &Path,
+		hooks_path: &Path,
+		trigger: SnapshotTrigger,
+		progress: &ProgressReporter,
 	) -> Result<()> {
 		let snapshot_dir = self
 			.path()
 			.join(snapshot_path)
 			.join(snapshot.uuid.to_string());
 		if !snapshot_dir.exists() {
-			return Err(anyhow!("snapshot {} does not exist", snapshot.uuid));
+			return Err(
+				Error::NotFound(format!("snapshot {} does not exist", snapshot.uuid)).into(),
+			);
 		}
-		let mut dir = fs::read_dir(&snapshot_dir)
-			.await
-			.with_context(|| format!("failed to read directory {}", snapshot_dir.display()))?;
-		while let Some(entry) = dir
-			.next_entry()
-			.await
-			.context("failed to read directory entry")?
-		{
-			let path = entry.path();
-			info!("deleting subvolume at {}", path.display());
-			tokio::task::spawn_blocking(move || {
-				libbtrfsutil::delete_subvolume(&path, DeleteSubvolumeFlags::empty())
-			})
-			.await?
-			.context("failed to delete subvolume")?;
-		}
-		fs::remove_dir_all(&snapshot_dir)
-			.await
-			.with_context(|| format!("failed to delete directory {}", snapshot_dir.display()))
+		progress.check_cancelled()?;
+		let hook_context = HookContext::new(HookOperation::Delete, snapshot, trigger.as_str());
+		hooks::run_hooks(hooks_path, HookStage::Pre, &hook_context).await?;
+		// Moving to the trash is a single rename per filesystem,
+		// so there's nothing finer-grained to report.
+		progress.add_steps(1);
+		self.trash_snapshot(snapshot, snapshot_path, trash_path)
+			.await?;
+		progress.complete_step();
+		hooks::run_hooks(hooks_path, HookStage::Post, &hook_context).await
 	}
 }
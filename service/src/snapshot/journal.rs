@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: MPL-2.0
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tokio::fs;
+use uuid::Uuid;
+
+/// The name of the restore journal, within the snapshot directory.
+pub const JOURNAL_FILE: &str = "restore-journal.json";
+
+/// A record of a snapshot being restored.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct RestoreRecord {
+	/// The snapshot that was restored.
+	pub snapshot: Uuid,
+	/// The snapshot that was taken of the replaced system state.
+	pub backup: Uuid,
+	#[serde(with = "time::serde::rfc3339")]
+	pub time: OffsetDateTime,
+	/// The subvolumes that were restored.
+	pub subvolumes: Vec<String>,
+}
+
+fn journal_path(snapshots_dir: &Path) -> PathBuf {
+	snapshots_dir.join(JOURNAL_FILE)
+}
+
+/// Reads every restore that's been done, oldest first.
+pub async fn read_journal(snapshots_dir: &Path) -> Result<Vec<RestoreRecord>> {
+	let path = journal_path(snapshots_dir);
+	if !path.exists() {
+		return Ok(Vec::new());
+	}
+	let json = fs::read_to_string(&path)
+		.await
+		.with_context(|| format!("failed to read {}", path.display()))?;
+	serde_json::from_str(&json).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Adds a restore to the journal.
+pub async fn record_restore(snapshots_dir: &Path, record: RestoreRecord) -> Result<()> {
+	let path = journal_path(snapshots_dir);
+	let mut journal = read_journal(snapshots_dir).await?;
+	journal.push(record);
+	fs::write(&path, serde_json::to_string_pretty(&journal)?)
+		.await
+		.with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Gets the snapshot the live system was last restored from, if any.
+///
+/// Failing to read the journal is logged, as lineage is informational,
+/// and shouldn't get in the way of anything else.
+pub async fn current_base(snapshots_dir: &Path) -> Option<Uuid> {
+	match read_journal(snapshots_dir).await {
+		Ok(journal) => journal.last().map(|record| record.snapshot),
+		Err(err) => {
+			warn!("Failed to read restore journal: {err:#}");
+			None
+		}
+	}
+}
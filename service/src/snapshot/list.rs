@@ -1,51 +1,317 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use super::{metadata::SnapshotMetadata, MountedBtrfs};
-use anyhow::{anyhow, Context, Result};
+use super::{create, metadata::SnapshotMetadata, MountedBtrfs};
+use anyhow::{Context, Result};
+use std::{
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+use time::OffsetDateTime;
 use tokio::fs;
+use uuid::Uuid;
+
+/// The directory, within the snapshot directory, that unreadable
+/// metadata files are moved into.
+pub const BROKEN_DIR: &str = "broken";
+
+/// A snapshot whose metadata file couldn't be read.
+pub struct BrokenSnapshot {
+	/// Metadata reconstructed, as well as possible, from the snapshot's directory.
+	pub metadata: SnapshotMetadata,
+	/// Why the metadata file couldn't be read.
+	pub error: String,
+	/// Whether the metadata file is in the [`BROKEN_DIR`] directory.
+	/// If moving it there failed, it's still in the snapshot directory.
+	pub quarantined: bool,
+}
 
 impl MountedBtrfs {
-	pub async fn list_snapshots(&self) -> Result<Vec<SnapshotMetadata>> {
+	/// Reads the metadata of every snapshot, migrating it from older
+	/// schema versions as needed.
+	///
+	/// If `rewrite_migrated` is set, migrated metadata is written back to disk.
+	///
+	/// Metadata that can't be read is moved into the [`BROKEN_DIR`] directory,
+	/// and returned separately, rather than failing the whole listing.
+	pub async fn list_snapshots(
+		&self,
+		snapshot_path: &Path,
+		rewrite_migrated: bool,
+	) -> Result<(Vec<SnapshotMetadata>, Vec<BrokenSnapshot>)> {
 		let mut snapshots = Vec::new();
-		let snapshot_dir = self.path().join("@snapshots/pop-snapshots");
+		let mut broken = Vec::new();
+		let snapshot_dir = self.path().join(snapshot_path);
 		if !snapshot_dir.exists() {
-			return Ok(Vec::new());
+			return Ok((snapshots, broken));
+		}
+		let broken_dir = snapshot_dir.join(BROKEN_DIR);
+		let mut metadata_files = list_metadata_files(&snapshot_dir).await?;
+		// Give previously quarantined metadata another chance,
+		// in case it's been fixed or this version can read it.
+		if broken_dir.is_dir() {
+			metadata_files.extend(list_metadata_files(&broken_dir).await?);
+		}
+		for path in metadata_files {
+			let result = match fs::read_to_string(&path).await {
+				Ok(json) => SnapshotMetadata::parse(&json),
+				Err(err) => Err(anyhow::Error::from(err).context("failed to read file")),
+			};
+			let (metadata, migrated) = match result {
+				Ok(result) => result,
+				Err(err) => {
+					let error = format!("{:#}", err);
+					error!(
+						"Failed to read snapshot metadata from {}: {error}",
+						path.display()
+					);
+					if let Some((metadata, quarantined)) =
+						self.quarantine_metadata(&path, snapshot_path).await
+					{
+						broken.push(BrokenSnapshot {
+							metadata,
+							error,
+							quarantined,
+						});
+					}
+					continue;
+				}
+			};
+			if path.starts_with(&broken_dir) {
+				info!(
+					"Metadata of snapshot {} is readable again, restoring it",
+					metadata.uuid
+				);
+				if let Err(err) = metadata.write(&snapshot_dir).await {
+					warn!("Failed to restore quarantined metadata: {err:#}");
+				} else if let Err(err) = fs::remove_file(&path).await {
+					warn!(
+						"Failed to remove quarantined metadata {}: {err}",
+						path.display()
+					);
+				}
+			} else if migrated && rewrite_migrated && !metadata.incomplete {
+				info!(
+					"Rewriting metadata of snapshot {} with schema version {}",
+					metadata.uuid, metadata.version
+				);
+				if let Err(err) = metadata.write(&snapshot_dir).await {
+					warn!("Failed to rewrite migrated metadata: {err:#}");
+				}
+			}
+			if let Some(restored) = metadata.replaced_by.filter(|_| metadata.incomplete) {
+				// This is the backup of a restore that was interrupted. It
+				// holds live subvolumes that were moved aside, so unlike
+				// leftovers of a snapshot, it mustn't be thrown away.
+				warn!(
+					"Restoring snapshot {restored} was interrupted, keeping what it replaced in snapshot {}",
+					metadata.uuid
+				);
+			} else if metadata.incomplete {
+				warn!(
+					"Snapshot {} was never finished, removing its leftovers",
+					metadata.uuid
+				);
+				self.discard_leftover_snapshot(&metadata, snapshot_path)
+					.await;
+				continue;
+			}
+			snapshots.push(metadata);
 		}
-		let mut dir = fs::read_dir(&snapshot_dir)
+		Ok((snapshots, broken))
+	}
+
+	/// Rebuilds the metadata of a snapshot from its directory of subvolumes.
+	///
+	/// Only the UUID, the subvolumes, and (approximately) the creation time
+	/// can be recovered.
+	pub async fn reconstruct_metadata(
+		&self,
+		uuid: Uuid,
+		snapshot_path: &Path,
+	) -> Result<SnapshotMetadata> {
+		let dir_path = self.path().join(snapshot_path).join(uuid.to_string());
+		let mut subvolumes = Vec::new();
+		let mut dir = fs::read_dir(&dir_path)
 			.await
-			.with_context(|| format!("failed to read directory {}", snapshot_dir.display()))?;
+			.with_context(|| format!("failed to read directory {}", dir_path.display()))?;
 		while let Some(entry) = dir
 			.next_entry()
 			.await
 			.context("failed to read directory entry")?
 		{
-			let path = entry.path();
-			if !path.is_file() {
-				continue;
+			let name = entry.file_name();
+			let name = name
+				.to_str()
+				.with_context(|| format!("invalid subvolume name {:?}", name))?;
+			subvolumes.push(name.replace("__", "/"));
+		}
+		subvolumes.sort();
+		let creation_time = fs::metadata(&dir_path)
+			.await
+			.and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+			.unwrap_or_else(|_| SystemTime::now());
+		let mut metadata = SnapshotMetadata::now(
+			None,
+			"Metadata was rebuilt from the snapshot's subvolumes".to_owned(),
+			subvolumes,
+		);
+		metadata.uuid = uuid;
+		metadata.creation_time = OffsetDateTime::from(creation_time);
+		Ok(metadata)
+	}
+
+	/// Moves an unreadable metadata file into the broken directory,
+	/// and reconstructs what it can of the snapshot it belonged to.
+	///
+	/// Returns the reconstructed metadata, and whether the file is now in the
+	/// broken directory, or `None` if it doesn't belong to a recognizable snapshot.
+	async fn quarantine_metadata(
+		&self,
+		path: &Path,
+		snapshot_path: &Path,
+	) -> Option<(SnapshotMetadata, bool)> {
+		let broken_dir = self.path().join(snapshot_path).join(BROKEN_DIR);
+		let file_name = path.file_name()?.to_str()?;
+		let uuid = match file_name
+			.strip_suffix(".snapshot.json")
+			.and_then(|uuid| Uuid::parse_str(uuid).ok())
+		{
+			Some(uuid) => uuid,
+			None => {
+				warn!(
+					"Ignoring metadata file {} with an invalid name",
+					path.display()
+				);
+				return None;
 			}
-			let name = match path
-				.file_name()
-				.and_then(|file_name_os| file_name_os.to_str())
-			{
-				Some(name) => name.to_owned(),
-				None => {
-					return Err(anyhow!(
-						"failed to get file name from path {}",
-						path.display()
-					));
+		};
+		let mut quarantined = true;
+		if !path.starts_with(&broken_dir) {
+			let quarantined_path = broken_dir.join(file_name);
+			info!(
+				"Moving unreadable metadata {} to {}",
+				path.display(),
+				quarantined_path.display()
+			);
+			let result = match fs::create_dir_all(&broken_dir).await {
+				Ok(()) => fs::rename(path, &quarantined_path).await,
+				Err(err) => Err(err),
+			};
+			if let Err(err) = result {
+				warn!("Failed to quarantine {}: {err}", path.display());
+				quarantined = false;
+			}
+		}
+		let metadata = match self.reconstruct_metadata(uuid, snapshot_path).await {
+			Ok(metadata) => metadata,
+			Err(err) => {
+				warn!("Failed to reconstruct metadata of snapshot {uuid}: {err:#}");
+				let mut metadata = SnapshotMetadata::now(None, None, Vec::new());
+				metadata.uuid = uuid;
+				metadata
+			}
+		};
+		Some((metadata, quarantined))
+	}
+
+	/// Cleans up a snapshot whose creation was interrupted before it could
+	/// be finalized, such as by a crash or power loss.
+	async fn discard_leftover_snapshot(&self, snapshot: &SnapshotMetadata, snapshot_path: &Path) {
+		let snapshots_dir = self.path().join(snapshot_path);
+		let other_mounts = match Self::mount_other_filesystems(snapshot).await {
+			Ok(mounts) => mounts,
+			Err(err) => {
+				warn!("Failed to mount the snapshot's other filesystems: {err:#}");
+				Vec::new()
+			}
+		};
+		let mut subvolumes = Vec::new();
+		for btrfs in std::iter::once(self).chain(&other_mounts) {
+			let snapshot_dir = btrfs
+				.path()
+				.join(snapshot_path)
+				.join(snapshot.uuid.to_string());
+			if let Ok(mut dir) = fs::read_dir(snapshot_dir).await {
+				while let Ok(Some(entry)) = dir.next_entry().await {
+					subvolumes.push(entry.path());
 				}
+			}
+		}
+		create::discard_incomplete_snapshot(snapshot, &snapshots_dir, &subvolumes).await;
+		create::discard_other_snapshot_dirs(snapshot, &other_mounts, snapshot_path).await;
+	}
+
+	/// Reads the metadata of the snapshots in the given pair.
+	///
+	/// Unlike [`Self::list_snapshots`], this leaves unreadable
+	/// metadata alone, and skips it.
+	pub async fn list_pair(
+		&self,
+		snapshot_path: &Path,
+		pair_id: Uuid,
+	) -> Result<Vec<SnapshotMetadata>> {
+		let mut snapshots = self.read_snapshots(snapshot_path).await?;
+		snapshots.retain(|snapshot| snapshot.pair.map(|pair| pair.id) == Some(pair_id));
+		Ok(snapshots)
+	}
+
+	/// Reads the metadata of every snapshot at `snapshot_path`,
+	/// logging and skipping any that can't be read.
+	pub(super) async fn read_snapshots(
+		&self,
+		snapshot_path: &Path,
+	) -> Result<Vec<SnapshotMetadata>> {
+		let snapshot_dir = self.path().join(snapshot_path);
+		let mut snapshots = Vec::new();
+		if !snapshot_dir.exists() {
+			return Ok(snapshots);
+		}
+		for path in list_metadata_files(&snapshot_dir).await? {
+			let result = match fs::read_to_string(&path).await {
+				Ok(json) => SnapshotMetadata::parse(&json),
+				Err(err) => Err(anyhow::Error::from(err).context("failed to read file")),
 			};
-			if !name.ends_with(".snapshot.json") {
-				continue;
+			match result {
+				Ok((metadata, _)) => snapshots.push(metadata),
+				Err(err) => warn!(
+					"Skipping unreadable snapshot metadata {}: {err:#}",
+					path.display()
+				),
 			}
-			let metadata: SnapshotMetadata = serde_json::from_str(
-				&fs::read_to_string(&path)
-					.await
-					.context(format!("failed to read file {}", path.display()))?,
-			)
-			.with_context(|| format!("failed to parse metadata from file {}", path.display()))?;
-			snapshots.push(metadata);
 		}
 		Ok(snapshots)
 	}
 }
+
+/// Lists the `*.snapshot.json` files directly within the given directory.
+pub(super) async fn list_metadata_files(dir_path: &Path) -> Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	let mut dir = fs::read_dir(dir_path)
+		.await
+		.with_context(|| format!("failed to read directory {}", dir_path.display()))?;
+	while let Some(entry) = dir
+		.next_entry()
+		.await
+		.context("failed to read directory entry")?
+	{
+		let path = entry.path();
+		if !path.is_file() {
+			continue;
+		}
+		let name = match path
+			.file_name()
+			.and_then(|file_name_os| file_name_os.to_str())
+		{
+			Some(name) => name.to_owned(),
+			None => {
+				warn!("Ignoring file with an invalid name: {}", path.display());
+				continue;
+			}
+		};
+		if !name.ends_with(".snapshot.json") {
+			continue;
+		}
+		files.push(path);
+	}
+	Ok(files)
+}
@@ -1,11 +1,34 @@
 // SPDX-License-Identifier: MPL-2.0
 
+use super::{
+	clone::CloneRecord, nested::NestedSubvolumePolicy, provenance::Provenance,
+	quiesce::QuiesceRecord,
+};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+/// The current version of the metadata schema.
+///
+/// Bump this, and add a migration to [`MIGRATIONS`], whenever existing
+/// metadata files need to be transformed to be read correctly.
+/// Adding a field with a default doesn't need a new version.
+pub const METADATA_VERSION: u32 = 1;
+
+/// Upgrades the raw metadata from one schema version to the next.
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades metadata from version `n` to version `n + 1`.
+const MIGRATIONS: [Migration; METADATA_VERSION as usize] = [migrate_v0_to_v1];
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct SnapshotMetadata {
+	/// The version of the schema this metadata was written with.
+	#[serde(default)]
+	pub version: u32,
 	pub uuid: Uuid,
 	#[serde(skip_serializing_if = "is_opt_string_empty")]
 	pub name: Option<String>,
@@ -13,9 +36,106 @@ pub struct SnapshotMetadata {
 	pub description: Option<String>,
 	#[serde(with = "time::serde::rfc3339")]
 	pub creation_time: OffsetDateTime,
+	/// The snapshotted subvolumes of the root filesystem.
+	pub subvolumes: Vec<String>,
+	/// What was done with nested subvolumes when this snapshot was taken.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub nested_subvolumes: Option<NestedSubvolumePolicy>,
+	/// The UUID of the root filesystem, if it was known.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub filesystem: Option<String>,
+	/// The snapshotted subvolumes of other btrfs filesystems.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub other_filesystems: Vec<FilesystemSubvolumes>,
+	/// Whether this snapshot is still being created.
+	///
+	/// A provisional metadata file is written with this set before any
+	/// subvolume is snapshotted, so leftovers from a failed or interrupted
+	/// creation can be found and cleaned up.
+	///
+	/// The backup taken while restoring is written the same way, but as it
+	/// holds the live subvolumes that were moved aside, it's kept instead.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub incomplete: bool,
+	/// Whether the filesystem was synced right before snapshotting.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub synced: bool,
+	/// The results of the quiesce hooks that ran around this snapshot.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub quiesce: Vec<QuiesceRecord>,
+	/// The pair of snapshots taken around an operation that this is part of.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub pair: Option<SnapshotPair>,
+	/// What triggered this snapshot, who asked for it, and what system it was taken on.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub provenance: Option<Provenance>,
+	/// Labels that can be used to find and filter snapshots.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub tags: Vec<String>,
+	/// Whether this snapshot is protected from deletion.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub protected: bool,
+	/// When this snapshot was deleted, if it's in the trash.
+	#[serde(
+		default,
+		with = "time::serde::rfc3339::option",
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub deleted_time: Option<OffsetDateTime>,
+	/// The writable clones that have been made of this snapshot.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub clones: Vec<CloneRecord>,
+	/// The snapshot the live system had last been restored from
+	/// when this snapshot was taken.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub parent: Option<Uuid>,
+	/// For a backup taken while restoring, the snapshot that was
+	/// restored in place of the state it captured.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub replaced_by: Option<Uuid>,
+	/// Any fields this version of the daemon doesn't know about,
+	/// such as ones written by a newer version.
+	///
+	/// These are kept so that they survive the metadata being rewritten.
+	#[serde(flatten)]
+	pub unknown: Map<String, Value>,
+}
+
+/// Subvolumes snapshotted from a btrfs filesystem other than the root one.
+///
+/// They're stored at the same snapshot path as on the root filesystem,
+/// but on their own filesystem.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct FilesystemSubvolumes {
+	/// The UUID of the filesystem, as in `/dev/disk/by-uuid`.
+	pub uuid: String,
 	pub subvolumes: Vec<String>,
 }
 
+/// Links a "pre" and "post" snapshot taken around the same operation,
+/// such as a system upgrade.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct SnapshotPair {
+	pub id: Uuid,
+	pub kind: PairKind,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum PairKind {
+	Pre,
+	Post,
+}
+
+impl PairKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Pre => "pre",
+			Self::Post => "post",
+		}
+	}
+}
+
 impl SnapshotMetadata {
 	pub fn now(
 		name: impl Into<Option<String>>,
@@ -23,13 +143,91 @@ impl SnapshotMetadata {
 		subvolumes: Vec<String>,
 	) -> Self {
 		SnapshotMetadata {
+			version: METADATA_VERSION,
 			uuid: Uuid::new_v4(),
 			name: name.into(),
 			description: description.into(),
 			creation_time: OffsetDateTime::now_utc(),
 			subvolumes,
+			nested_subvolumes: None,
+			filesystem: None,
+			other_filesystems: Vec::new(),
+			incomplete: false,
+			synced: false,
+			quiesce: Vec::new(),
+			pair: None,
+			provenance: None,
+			tags: Vec::new(),
+			protected: false,
+			deleted_time: None,
+			clones: Vec::new(),
+			parent: None,
+			replaced_by: None,
+			unknown: Map::new(),
 		}
 	}
+
+	/// Parses metadata, upgrading it from older schema versions if needed.
+	///
+	/// Also returns whether any migrations were applied,
+	/// in which case the file should be rewritten.
+	pub fn parse(json: &str) -> Result<(Self, bool)> {
+		let mut value: Value = serde_json::from_str(json).context("metadata is not valid JSON")?;
+		let object = value
+			.as_object_mut()
+			.context("metadata is not a JSON object")?;
+		let version = match object.get("version") {
+			Some(version) => version
+				.as_u64()
+				.and_then(|version| u32::try_from(version).ok())
+				.context("metadata has an invalid version")?,
+			// The original schema had no version field.
+			None => 0,
+		};
+		if version > METADATA_VERSION {
+			warn!(
+				"Metadata has schema version {version}, newer than the supported version {}; \
+				 unknown fields will be preserved, but may be ignored",
+				METADATA_VERSION
+			);
+		}
+		let migrated = version < METADATA_VERSION;
+		for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+			migration(object).with_context(|| {
+				format!(
+					"failed to migrate metadata from version {} to {}",
+					from,
+					from + 1
+				)
+			})?;
+		}
+		if migrated {
+			object.insert("version".into(), METADATA_VERSION.into());
+		}
+		let metadata = serde_json::from_value(value).context("metadata does not match schema")?;
+		Ok((metadata, migrated))
+	}
+
+	/// The path of the metadata file for this snapshot,
+	/// within the given snapshot directory.
+	pub fn path(&self, snapshot_dir: &Path) -> PathBuf {
+		snapshot_dir
+			.join(self.uuid.to_string())
+			.with_extension("snapshot.json")
+	}
+
+	/// Writes this metadata to its file within the given snapshot directory.
+	pub async fn write(&self, snapshot_dir: &Path) -> Result<()> {
+		let metadata_path = self.path(snapshot_dir);
+		tokio::fs::write(&metadata_path, serde_json::to_string_pretty(self)?)
+			.await
+			.with_context(|| {
+				format!(
+					"failed to write snapshot metadata to '{}'",
+					metadata_path.display()
+				)
+			})
+	}
 }
 
 impl PartialOrd for SnapshotMetadata {
@@ -44,9 +242,95 @@ impl Ord for SnapshotMetadata {
 	}
 }
 
+/// Trims, deduplicates and sorts tags, dropping any empty ones.
+pub fn normalize_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+	let mut tags = tags
+		.into_iter()
+		.map(|tag| tag.trim().to_owned())
+		.filter(|tag| !tag.is_empty())
+		.collect::<Vec<_>>();
+	tags.sort();
+	tags.dedup();
+	tags
+}
+
+/// Version 1 only introduced the `version` field itself.
+/// Every field added since the original schema has a default,
+/// so there's nothing else to transform.
+fn migrate_v0_to_v1(_metadata: &mut Map<String, Value>) -> Result<()> {
+	Ok(())
+}
+
 fn is_opt_string_empty(val: &Option<String>) -> bool {
 	match val {
 		Some(val) => val.trim().is_empty(),
 		None => true,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	const V0: &str = include_str!("../../tests/fixtures/metadata/v0.snapshot.json");
+	const V0_MIGRATED: &str =
+		include_str!("../../tests/fixtures/metadata/v0-migrated.snapshot.json");
+	const V1: &str = include_str!("../../tests/fixtures/metadata/v1.snapshot.json");
+
+	/// Writes metadata the way the daemon does, and reads back the file.
+	async fn write_to_string(metadata: &SnapshotMetadata) -> String {
+		let dir = tempfile::tempdir().expect("failed to create temporary directory");
+		metadata
+			.write(dir.path())
+			.await
+			.expect("failed to write metadata");
+		tokio::fs::read_to_string(metadata.path(dir.path()))
+			.await
+			.expect("failed to read metadata back")
+	}
+
+	#[tokio::test]
+	async fn migrates_v0_to_current_version() {
+		let (metadata, migrated) = SnapshotMetadata::parse(V0).expect("failed to parse v0");
+		assert!(migrated);
+		assert_eq!(metadata.version, METADATA_VERSION);
+		assert_eq!(write_to_string(&metadata).await, V0_MIGRATED.trim_end());
+	}
+
+	#[tokio::test]
+	async fn migrated_metadata_is_stable() {
+		let (metadata, _) = SnapshotMetadata::parse(V0).expect("failed to parse v0");
+		let written = write_to_string(&metadata).await;
+		let (reparsed, migrated) =
+			SnapshotMetadata::parse(&written).expect("failed to parse migrated metadata");
+		assert!(!migrated);
+		assert_eq!(reparsed, metadata);
+		assert_eq!(write_to_string(&reparsed).await, written);
+	}
+
+	#[tokio::test]
+	async fn current_version_round_trips_byte_for_byte() {
+		let (metadata, migrated) = SnapshotMetadata::parse(V1).expect("failed to parse v1");
+		assert!(!migrated);
+		assert_eq!(write_to_string(&metadata).await, V1.trim_end());
+	}
+
+	#[test]
+	fn keeps_unknown_fields() {
+		let (v0, _) = SnapshotMetadata::parse(V0).expect("failed to parse v0");
+		assert_eq!(v0.unknown.len(), 2);
+		assert_eq!(v0.unknown["another_unknown"], json!("kept"));
+		assert_eq!(v0.unknown["from_the_future"], json!({ "answer": 42 }));
+
+		let (v1, _) = SnapshotMetadata::parse(V1).expect("failed to parse v1");
+		assert_eq!(v1.unknown.len(), 1);
+		assert_eq!(v1.unknown["unknown_from_v2"], json!([1, 2]));
+	}
+
+	#[test]
+	fn rejects_invalid_versions() {
+		assert!(SnapshotMetadata::parse(r#"{"version": "one"}"#).is_err());
+		assert!(SnapshotMetadata::parse(r#"{"version": -1}"#).is_err());
+	}
+}
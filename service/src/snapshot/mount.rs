@@ -1,33 +1,61 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use super::MountedBtrfs;
+use super::{metadata::SnapshotMetadata, MountedBtrfs};
 use crate::util::find_root_device;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use libbtrfsutil::CreateSubvolumeFlags;
+use std::path::{Path, PathBuf};
 use sys_mount::{FilesystemType, Mount, UnmountFlags};
 
 impl MountedBtrfs {
 	/// Mounts the base subvolume of the root btrfs partition in
 	/// a temporary directory.
 	pub async fn new() -> Result<Self> {
-		let tempdir = tempfile::tempdir().context("failed to create tempdir")?;
 		let root_device_path = find_root_device()
 			.await
 			.context("failed to find root device")?;
 		debug!("Found root device path at {}", root_device_path.display());
+		Self::mount(root_device_path).await
+	}
+
+	/// Mounts the base subvolume of the btrfs filesystem with the given UUID
+	/// in a temporary directory.
+	pub async fn with_uuid(uuid: &str) -> Result<Self> {
+		let device_path = Path::new("/dev/disk/by-uuid").join(uuid);
+		if !device_path.exists() {
+			return Err(anyhow!("no filesystem with the UUID {} was found", uuid));
+		}
+		Self::mount(device_path).await
+	}
+
+	/// Mounts the base subvolume of each filesystem, other than the root one,
+	/// that the given snapshot has subvolumes on, in the same order.
+	pub async fn mount_other_filesystems(snapshot: &SnapshotMetadata) -> Result<Vec<Self>> {
+		let mut mounts = Vec::with_capacity(snapshot.other_filesystems.len());
+		for filesystem in &snapshot.other_filesystems {
+			let btrfs = Self::with_uuid(&filesystem.uuid)
+				.await
+				.with_context(|| format!("failed to mount filesystem {}", filesystem.uuid))?;
+			mounts.push(btrfs);
+		}
+		Ok(mounts)
+	}
+
+	async fn mount(device_path: PathBuf) -> Result<Self> {
+		let tempdir = tempfile::tempdir().context("failed to create tempdir")?;
 		let tempdir_path = tempdir.path().to_path_buf();
 		let snapshots_path = tempdir_path.join("@snapshots");
 
 		debug!(
 			"Mounting {}[subvol=/] at {}",
-			root_device_path.display(),
+			device_path.display(),
 			tempdir_path.display()
 		);
 		let mount = tokio::task::spawn_blocking(move || {
 			Mount::builder()
 				.fstype(FilesystemType::Manual("btrfs"))
 				.data("subvol=/")
-				.mount_autodrop(root_device_path, tempdir_path, UnmountFlags::DETACH)
+				.mount_autodrop(device_path, tempdir_path, UnmountFlags::DETACH)
 		})
 		.await?
 		.context("failed to mount root subvolume")?;
@@ -45,7 +73,7 @@ impl MountedBtrfs {
 		}
 
 		Ok(MountedBtrfs {
-			_mount: mount,
+			_mount: Some(mount),
 			tempdir,
 		})
 	}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MPL-2.0
+use anyhow::{Context, Result};
+use libbtrfsutil::{SubvolumeIterator, SubvolumeIteratorFlags};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// What to do with subvolumes nested within other snapshotted subvolumes,
+/// such as `@root/var/lib/docker/...` within `@root`.
+///
+/// Snapshotting a subvolume doesn't capture the subvolumes nested within it;
+/// they're left as empty directories in the snapshot.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum NestedSubvolumePolicy {
+	/// Don't snapshot nested subvolumes. When restoring, they stay with
+	/// the replaced parent, in the backup snapshot.
+	Skip,
+	/// Snapshot nested subvolumes separately, and put them back
+	/// in place within their parent when restoring.
+	Recursive,
+	/// Don't snapshot nested subvolumes. When restoring, the live nested
+	/// subvolumes are moved into the restored parent, so they're unaffected
+	/// by the restore.
+	CarryOver,
+}
+
+impl Default for NestedSubvolumePolicy {
+	fn default() -> Self {
+		Self::CarryOver
+	}
+}
+
+impl NestedSubvolumePolicy {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Skip => "skip",
+			Self::Recursive => "recursive",
+			Self::CarryOver => "carry-over",
+		}
+	}
+
+	/// Picks which of the given subvolumes should be snapshotted.
+	pub fn filter(&self, subvolumes: Vec<String>) -> Vec<String> {
+		if *self == Self::Recursive {
+			return subvolumes;
+		}
+		subvolumes
+			.iter()
+			.filter(|subvolume| match nested_parent(subvolume, &subvolumes) {
+				Some(parent) => {
+					debug!("Skipping subvolume '{subvolume}', it's nested within '{parent}'");
+					false
+				}
+				None => true,
+			})
+			.cloned()
+			.collect()
+	}
+}
+
+/// Finds the innermost of `subvolumes` that `subvolume` is nested within, if any.
+pub fn nested_parent<'a>(subvolume: &str, subvolumes: &'a [String]) -> Option<&'a str> {
+	subvolumes
+		.iter()
+		.filter(|parent| parent.as_str() != subvolume && Path::new(subvolume).starts_with(parent))
+		.max_by_key(|parent| parent.len())
+		.map(String::as_str)
+}
+
+/// Lists the subvolumes nested directly within the subvolume at `path`,
+/// relative to it.
+///
+/// Subvolumes nested within those are left out, as they move along with them.
+pub fn list_nested_subvolumes(path: &Path) -> Result<Vec<PathBuf>> {
+	let iter = SubvolumeIterator::new(path, None, SubvolumeIteratorFlags::empty())
+		.with_context(|| format!("failed to iterate subvolumes in {}", path.display()))?;
+	let mut nested = Vec::new();
+	for subvolume in iter {
+		let (subvolume_path, _) = subvolume.context("failed to get subvolume")?;
+		nested.push(subvolume_path);
+	}
+	let outermost = nested
+		.iter()
+		.filter(|subvolume| {
+			!nested
+				.iter()
+				.any(|parent| parent != *subvolume && subvolume.starts_with(parent))
+		})
+		.cloned()
+		.collect();
+	Ok(outermost)
+}
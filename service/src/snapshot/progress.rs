@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use std::{fmt, sync::Arc};
+use tokio::sync::watch;
+
+/// The error returned when an operation stops because it was cancelled.
+#[derive(Debug, Clone, Copy)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("the operation was cancelled")
+	}
+}
+
+impl std::error::Error for Cancelled {}
+
+/// How far along a long-running operation is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Progress {
+	pub completed: usize,
+	pub total: usize,
+	/// The subvolume currently being worked on, if any.
+	pub current_subvolume: Option<String>,
+	/// Whether the operation is waiting for others to finish first.
+	pub queued: bool,
+}
+
+impl Progress {
+	/// The fraction of the work that's done, from 0 to 1.
+	pub fn fraction(&self) -> f64 {
+		if self.total == 0 {
+			0.0
+		} else {
+			(self.completed as f64 / self.total as f64).min(1.0)
+		}
+	}
+}
+
+/// Reports the progress of an operation to whoever's watching it,
+/// and lets them ask for it to be cancelled.
+///
+/// The default reporter doesn't report to anyone, and is never cancelled,
+/// for operations that aren't being watched.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter {
+	sender: Option<Arc<watch::Sender<Progress>>>,
+	cancel: Option<watch::Receiver<bool>>,
+}
+
+impl ProgressReporter {
+	/// Creates a reporter for an operation that's cancelled
+	/// once `cancel` becomes true.
+	pub fn new(cancel: watch::Receiver<bool>) -> (Self, watch::Receiver<Progress>) {
+		let (sender, receiver) = watch::channel(Progress::default());
+		(
+			Self {
+				sender: Some(Arc::new(sender)),
+				cancel: Some(cancel),
+			},
+			receiver,
+		)
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancel
+			.as_ref()
+			.map_or(false, |cancel| *cancel.borrow())
+	}
+
+	/// Fails with [`Cancelled`] if the operation has been cancelled.
+	///
+	/// Operations check this between steps, wherever it's still
+	/// safe to stop and roll back.
+	pub fn check_cancelled(&self) -> anyhow::Result<()> {
+		if self.is_cancelled() {
+			return Err(Cancelled.into());
+		}
+		Ok(())
+	}
+
+	/// Waits until the operation is cancelled, which may be never.
+	pub async fn cancelled(&self) {
+		match self.cancel.clone() {
+			Some(mut cancel) => {
+				while !*cancel.borrow() {
+					if cancel.changed().await.is_err() {
+						// Nobody can cancel it anymore.
+						std::future::pending::<()>().await;
+					}
+				}
+			}
+			None => std::future::pending().await,
+		}
+	}
+
+	fn update(&self, modify: impl FnOnce(&mut Progress)) {
+		if let Some(sender) = &self.sender {
+			sender.send_modify(modify);
+		}
+	}
+
+	/// Marks the operation as waiting for others to finish, or not.
+	pub fn set_queued(&self, queued: bool) {
+		self.update(|progress| progress.queued = queued);
+	}
+
+	/// Adds more steps to the total amount of work.
+	pub fn add_steps(&self, steps: usize) {
+		self.update(|progress| progress.total += steps);
+	}
+
+	/// Marks the start of the work on a subvolume.
+	pub fn start_subvolume(&self, subvolume: &str) {
+		self.update(|progress| progress.current_subvolume = Some(subvolume.to_owned()));
+	}
+
+	/// Marks a step as completed.
+	pub fn complete_step(&self) {
+		self.update(|progress| {
+			progress.completed += 1;
+			progress.current_subvolume = None;
+		});
+	}
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::util::Caller;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::fs;
+
+/// What caused a snapshot to be taken.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotTrigger {
+	/// Explicitly requested by a user.
+	Manual,
+	/// Taken on a schedule.
+	Schedule,
+	/// Taken by the package manager, such as before an upgrade.
+	Apt,
+	/// Taken automatically to back up the system state while restoring another snapshot.
+	RestoreBackup,
+	/// Imported from elsewhere.
+	Import,
+}
+
+impl SnapshotTrigger {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Manual => "manual",
+			Self::Schedule => "schedule",
+			Self::Apt => "apt",
+			Self::RestoreBackup => "restore-backup",
+			Self::Import => "import",
+		}
+	}
+}
+
+impl FromStr for SnapshotTrigger {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"manual" => Ok(Self::Manual),
+			"schedule" => Ok(Self::Schedule),
+			"apt" => Ok(Self::Apt),
+			"restore-backup" => Ok(Self::RestoreBackup),
+			"import" => Ok(Self::Import),
+			_ => Err(anyhow!("unknown snapshot trigger '{}'", s)),
+		}
+	}
+}
+
+/// Where a snapshot came from: what triggered it, who asked for it,
+/// and what system it was taken on.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Provenance {
+	pub trigger: SnapshotTrigger,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub sender_uid: Option<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub sender_process: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub hostname: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub kernel: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub os_version: Option<String>,
+}
+
+impl Provenance {
+	/// Gathers the provenance of a snapshot being taken right now.
+	///
+	/// Any details that can't be determined are logged and left out,
+	/// as they shouldn't prevent a snapshot from being taken.
+	pub async fn collect(trigger: SnapshotTrigger, caller: Option<Caller>) -> Self {
+		let (sender_uid, sender_process) = match caller {
+			Some(caller) => (Some(caller.uid), caller.process),
+			None => (None, None),
+		};
+		Self {
+			trigger,
+			sender_uid,
+			sender_process,
+			hostname: read_trimmed("/proc/sys/kernel/hostname").await,
+			kernel: read_trimmed("/proc/sys/kernel/osrelease").await,
+			os_version: os_version().await,
+		}
+	}
+}
+
+async fn read_trimmed(path: &str) -> Option<String> {
+	match fs::read_to_string(path).await {
+		Ok(contents) => Some(contents.trim().to_owned()),
+		Err(err) => {
+			warn!("Failed to read {path}: {err}");
+			None
+		}
+	}
+}
+
+/// Gets the human-readable OS version from `/etc/os-release`.
+async fn os_version() -> Option<String> {
+	let os_release = read_trimmed("/etc/os-release").await?;
+	let field = |key: &str| {
+		os_release.lines().find_map(|line| {
+			let value = line.strip_prefix(key)?.strip_prefix('=')?;
+			Some(value.trim_matches('"').to_owned())
+		})
+	};
+	field("PRETTY_NAME").or_else(|| field("NAME"))
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::config::QuiesceHook;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::File,
+	os::unix::io::AsRawFd,
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
+use tokio::process::Command;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuiesceStage {
+	Freeze,
+	Thaw,
+}
+
+/// The result of running a single quiesce hook command.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct QuiesceRecord {
+	pub hook: String,
+	pub stage: QuiesceStage,
+	pub success: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	pub duration_ms: u64,
+}
+
+/// Flushes all pending writes of the filesystem containing `path` to disk.
+pub async fn syncfs(path: &Path) -> Result<()> {
+	let path = path.to_path_buf();
+	tokio::task::spawn_blocking(move || {
+		let file =
+			File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+		if unsafe { libc::syncfs(file.as_raw_fd()) } != 0 {
+			return Err(std::io::Error::last_os_error())
+				.with_context(|| format!("failed to sync filesystem at {}", path.display()));
+		}
+		Ok(())
+	})
+	.await?
+}
+
+/// Runs the freeze command of each hook, in order.
+///
+/// The result of every hook that was run is added to `records`. If a required
+/// hook fails, any hooks that were already frozen are thawed again and an
+/// error is returned.
+pub async fn freeze(hooks: &[QuiesceHook], records: &mut Vec<QuiesceRecord>) -> Result<()> {
+	for (idx, hook) in hooks.iter().enumerate() {
+		let record = run_hook(hook, QuiesceStage::Freeze).await;
+		let failed = !record.success;
+		let error = record.error.clone().unwrap_or_default();
+		records.push(record);
+		if failed && hook.required {
+			thaw(&hooks[..idx], records).await;
+			return Err(anyhow!(
+				"required quiesce hook '{}' failed: {}",
+				hook.name,
+				error
+			));
+		}
+	}
+	Ok(())
+}
+
+/// Runs the thaw command of each hook, in reverse order.
+///
+/// Failures are recorded and logged, but never abort, so every
+/// hook gets a chance to resume.
+pub async fn thaw(hooks: &[QuiesceHook], records: &mut Vec<QuiesceRecord>) {
+	for hook in hooks.iter().rev() {
+		if hook.thaw.is_empty() {
+			continue;
+		}
+		records.push(run_hook(hook, QuiesceStage::Thaw).await);
+	}
+}
+
+async fn run_hook(hook: &QuiesceHook, stage: QuiesceStage) -> QuiesceRecord {
+	let command = match stage {
+		QuiesceStage::Freeze => &hook.freeze,
+		QuiesceStage::Thaw => &hook.thaw,
+	};
+	info!("Running {stage:?} command of quiesce hook '{}'", hook.name);
+	let start = Instant::now();
+	let result = run_command(command, Duration::from_secs(hook.timeout)).await;
+	let duration_ms = start.elapsed().as_millis() as u64;
+	if let Err(err) = &result {
+		warn!(
+			"{stage:?} command of quiesce hook '{}' failed: {err:#}",
+			hook.name
+		);
+	}
+	QuiesceRecord {
+		hook: hook.name.clone(),
+		stage,
+		success: result.is_ok(),
+		error: result.err().map(|err| format!("{:#}", err)),
+		duration_ms,
+	}
+}
+
+async fn run_command(command: &[String], timeout: Duration) -> Result<()> {
+	let (program, args) = command.split_first().context("command is empty")?;
+	let program = PathBuf::from(program);
+	let output = Command::new(&program)
+		.args(args)
+		.kill_on_drop(true)
+		.output();
+	let output = tokio::time::timeout(timeout, output)
+		.await
+		.with_context(|| format!("timed out after {} seconds", timeout.as_secs()))?
+		.with_context(|| format!("failed to run {}", program.display()))?;
+	if !output.status.success() {
+		return Err(anyhow!(
+			"{} exited with {}: {}",
+			program.display(),
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim()
+		));
+	}
+	Ok(())
+}
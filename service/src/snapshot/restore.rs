@@ -1,25 +1,76 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use super::{metadata::SnapshotMetadata, MountedBtrfs};
+use super::{
+	create,
+	journal::{self, RestoreRecord},
+	metadata::SnapshotMetadata,
+	nested::{list_nested_subvolumes, nested_parent, NestedSubvolumePolicy},
+	progress::ProgressReporter,
+	provenance::{Provenance, SnapshotTrigger},
+	MountedBtrfs,
+};
+use crate::{
+	hooks::{self, HookContext, HookOperation, HookStage},
+	util::root_filesystem_uuid,
+};
 use anyhow::{anyhow, Context, Result};
-use libbtrfsutil::CreateSnapshotFlags;
-use std::path::Path;
+use libbtrfsutil::{CreateSnapshotFlags, DeleteSubvolumeFlags};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
 use tokio::fs;
+use zbus_pop_snapshot::Error;
 
 impl MountedBtrfs {
+	/// Restores the system to a snapshot, returning the backup snapshot
+	/// of the state it replaced.
+	///
+	/// `provenance` is that of the caller, whose trigger is passed to the
+	/// hooks. The backup gets the same provenance, as a `restore-backup`.
 	pub async fn restore_snapshot(
 		&self,
 		snapshot: &SnapshotMetadata,
 		snapshot_path: &Path,
+		hooks_path: &Path,
+		provenance: Provenance,
+		nested_policy: NestedSubvolumePolicy,
+		progress: &ProgressReporter,
 	) -> Result<SnapshotMetadata> {
 		let restore_snapshot_dir = self
 			.path()
 			.join(snapshot_path)
 			.join(snapshot.uuid.to_string());
 		if !restore_snapshot_dir.exists() {
-			return Err(anyhow!("snapshot {} does not exist", snapshot.uuid));
+			return Err(
+				Error::NotFound(format!("snapshot {} does not exist", snapshot.uuid)).into(),
+			);
+		}
+		// Mount everything up front, so a missing disk is noticed
+		// before anything is changed.
+		let other_mounts = Self::mount_other_filesystems(snapshot).await?;
+		for (filesystem, btrfs) in snapshot.other_filesystems.iter().zip(&other_mounts) {
+			let dir = btrfs
+				.path()
+				.join(snapshot_path)
+				.join(snapshot.uuid.to_string());
+			if !dir.exists() {
+				return Err(anyhow!(
+					"snapshot {} does not exist on filesystem {}",
+					snapshot.uuid,
+					filesystem.uuid
+				));
+			}
 		}
-		let new_snapshot = SnapshotMetadata::now(
+		// Past this point, the live subvolumes start being replaced, so the
+		// restore can't be cancelled anymore. This is checked before the
+		// pre-hooks, so cancelling never skips the post-hooks after them.
+		progress.check_cancelled()?;
+		let mut hook_context = HookContext::new(
+			HookOperation::Restore,
+			snapshot,
+			provenance.trigger.as_str(),
+		);
+		hooks::run_hooks(hooks_path, HookStage::Pre, &hook_context).await?;
+		let mut new_snapshot = SnapshotMetadata::now(
 			None,
 			format!(
 				"Automatic snapshot made when restoring snapshot {}",
@@ -27,80 +78,314 @@ impl MountedBtrfs {
 			),
 			snapshot.subvolumes.clone(),
 		);
-		let new_snapshot_dir = self
-			.path()
-			.join(snapshot_path)
-			.join(new_snapshot.uuid.to_string());
-		if !new_snapshot_dir.exists() {
-			fs::create_dir_all(&new_snapshot_dir)
+		new_snapshot.provenance = Some(Provenance {
+			trigger: SnapshotTrigger::RestoreBackup,
+			..provenance
+		});
+		// Snapshots taken before the policy was recorded are restored
+		// with the configured one.
+		let nested_policy = snapshot.nested_subvolumes.unwrap_or(nested_policy);
+		new_snapshot.nested_subvolumes = Some(nested_policy);
+		new_snapshot.filesystem = root_filesystem_uuid().await.ok();
+		new_snapshot.other_filesystems = snapshot.other_filesystems.clone();
+		let snapshots_dir = self.path().join(snapshot_path);
+		new_snapshot.parent = journal::current_base(&snapshots_dir).await;
+		new_snapshot.replaced_by = Some(snapshot.uuid);
+		let carry_over = nested_policy == NestedSubvolumePolicy::CarryOver;
+		progress.add_steps(
+			snapshot.subvolumes.len()
+				+ snapshot
+					.other_filesystems
+					.iter()
+					.map(|filesystem| filesystem.subvolumes.len())
+					.sum::<usize>(),
+		);
+		// Write provisional metadata first, so that if we're interrupted,
+		// the live subvolumes that were already moved aside can be found.
+		new_snapshot.incomplete = true;
+		new_snapshot
+			.write(&snapshots_dir)
+			.await
+			.context("failed to write provisional backup metadata")?;
+		let mut root_steps = Vec::new();
+		let mut other_steps = other_mounts.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+		let result: Result<()> = async {
+			self.restore_subvolumes(
+				&snapshot.subvolumes,
+				&restore_snapshot_dir,
+				&snapshots_dir.join(new_snapshot.uuid.to_string()),
+				carry_over,
+				progress,
+				&mut root_steps,
+			)
+			.await?;
+			for ((filesystem, btrfs), steps) in snapshot
+				.other_filesystems
+				.iter()
+				.zip(&other_mounts)
+				.zip(&mut other_steps)
+			{
+				let snapshots_dir = btrfs.path().join(snapshot_path);
+				btrfs
+					.restore_subvolumes(
+						&filesystem.subvolumes,
+						&snapshots_dir.join(snapshot.uuid.to_string()),
+						&snapshots_dir.join(new_snapshot.uuid.to_string()),
+						carry_over,
+						progress,
+						steps,
+					)
+					.await
+					.with_context(|| format!("failed to restore filesystem {}", filesystem.uuid))?;
+			}
+			Ok(())
+		}
+		.await;
+		if let Err(err) = result {
+			// Put every filesystem back the way it was, so the system
+			// isn't left with only some of the snapshot restored.
+			let mut undone = true;
+			for steps in other_steps.into_iter().rev() {
+				undone &= undo_restore_steps(steps).await;
+			}
+			undone &= undo_restore_steps(root_steps).await;
+			if undone {
+				create::discard_incomplete_snapshot(&new_snapshot, &snapshots_dir, &[]).await;
+				create::discard_other_snapshot_dirs(&new_snapshot, &other_mounts, snapshot_path)
+					.await;
+			} else {
+				warn!(
+					"Failed to undo the restore, the replaced subvolumes are kept in snapshot {}",
+					new_snapshot.uuid
+				);
+			}
+			return Err(err);
+		}
+
+		info!(
+			"writing new snapshot metadata to {}",
+			new_snapshot.path(&snapshots_dir).display()
+		);
+		new_snapshot.incomplete = false;
+		new_snapshot.write(&snapshots_dir).await?;
+		// The restore has already happened, so failing to record it
+		// shouldn't fail the whole thing.
+		let record = RestoreRecord {
+			snapshot: snapshot.uuid,
+			backup: new_snapshot.uuid,
+			time: OffsetDateTime::now_utc(),
+			subvolumes: snapshot.subvolumes.clone(),
+		};
+		if let Err(err) = journal::record_restore(&snapshots_dir, record).await {
+			warn!("Failed to record restore in the journal: {err:#}");
+		}
+		hook_context.backup_uuid = Some(new_snapshot.uuid);
+		hooks::run_hooks(hooks_path, HookStage::Post, &hook_context).await?;
+
+		Ok(new_snapshot)
+	}
+
+	/// Replaces each live subvolume with a writable snapshot of its copy in
+	/// `restore_snapshot_dir`, moving the live subvolume into `backup_dir`.
+	///
+	/// Nested subvolumes in the list are put back in place within their
+	/// restored parent. If `carry_over` is set, live nested subvolumes that
+	/// aren't in the list are moved into the restored parent.
+	///
+	/// Each change is added to `steps` as it's made, so that it can be
+	/// undone if this, or the restore of another filesystem, fails.
+	async fn restore_subvolumes(
+		&self,
+		subvolumes: &[String],
+		restore_snapshot_dir: &Path,
+		backup_dir: &Path,
+		carry_over: bool,
+		progress: &ProgressReporter,
+		steps: &mut Vec<RestoreStep>,
+	) -> Result<()> {
+		if !backup_dir.exists() {
+			fs::create_dir_all(backup_dir)
+				.await
+				.with_context(|| format!("failed to create directory {}", backup_dir.display()))?;
+		}
+		let (nested, top_level): (Vec<&String>, Vec<&String>) = subvolumes
+			.iter()
+			.partition(|subvolume| nested_parent(subvolume, subvolumes).is_some());
+		// Find the nested subvolumes to carry over before anything moves.
+		let mut carried_over = Vec::new();
+		if carry_over {
+			for parent in &top_level {
+				let path = self.path().join(parent);
+				let found =
+					tokio::task::spawn_blocking(move || list_nested_subvolumes(&path)).await??;
+				carried_over.extend(found.into_iter().map(|relative| (*parent, relative)));
+			}
+		}
+
+		for subvolume in top_level {
+			progress.start_subvolume(subvolume);
+			let live_path = self.path().join(subvolume);
+			let backup_path = backup_dir.join(subvolume.replace('/', "__"));
+			info!("{} -> {}", live_path.display(), backup_path.display());
+			fs::rename(&live_path, &backup_path)
 				.await
 				.with_context(|| {
-					format!("failed to create directory {}", new_snapshot_dir.display())
+					format!(
+						"failed to rename {} to {}",
+						live_path.display(),
+						backup_path.display()
+					)
 				})?;
+			steps.push(RestoreStep::Moved(live_path.clone(), backup_path));
+			self.restore_subvolume(subvolume, restore_snapshot_dir)
+				.await?;
+			steps.push(RestoreStep::Restored(live_path));
+			progress.complete_step();
+		}
+
+		// Parents sort before the subvolumes nested within them.
+		let mut nested = nested;
+		nested.sort();
+		for subvolume in nested {
+			progress.start_subvolume(subvolume);
+			let parent = nested_parent(subvolume, subvolumes).expect("subvolume is nested");
+			let relative = Path::new(subvolume)
+				.strip_prefix(parent)
+				.expect("subvolume is nested within its parent");
+			// The live subvolume went along with its parent into the backup.
+			let live_path = backup_dir.join(parent.replace('/', "__")).join(relative);
+			let backup_path = backup_dir.join(subvolume.replace('/', "__"));
+			if live_path.exists() {
+				info!("{} -> {}", live_path.display(), backup_path.display());
+				fs::rename(&live_path, &backup_path)
+					.await
+					.with_context(|| {
+						format!(
+							"failed to rename {} to {}",
+							live_path.display(),
+							backup_path.display()
+						)
+					})?;
+				steps.push(RestoreStep::Moved(live_path, backup_path));
+			}
+			remove_placeholder(&self.path().join(subvolume)).await?;
+			self.restore_subvolume(subvolume, restore_snapshot_dir)
+				.await?;
+			steps.push(RestoreStep::Restored(self.path().join(subvolume)));
+			progress.complete_step();
 		}
-		for subvolume in &snapshot.subvolumes {
-			let restore_target_subvolume_path =
-				restore_snapshot_dir.join(subvolume.replace('/', "__"));
-			let new_snapshot_subvolume_path = new_snapshot_dir.join(subvolume.replace('/', "__"));
-			let subvolume_path = self.path().join(subvolume);
+
+		for (parent, relative) in carried_over {
+			let live_path = backup_dir.join(parent.replace('/', "__")).join(&relative);
+			let target_path = self.path().join(parent).join(&relative);
 			info!(
-				"{} -> {}",
-				subvolume_path.display(),
-				new_snapshot_subvolume_path.display()
+				"Carrying over nested subvolume {} to {}",
+				live_path.display(),
+				target_path.display()
 			);
-			fs::rename(&subvolume_path, &new_snapshot_subvolume_path)
+			remove_placeholder(&target_path).await?;
+			if let Some(target_dir) = target_path.parent() {
+				fs::create_dir_all(target_dir).await.with_context(|| {
+					format!("failed to create directory {}", target_dir.display())
+				})?;
+			}
+			fs::rename(&live_path, &target_path)
 				.await
 				.with_context(|| {
 					format!(
-						"failed to rename {} to {}",
-						subvolume_path.display(),
-						new_snapshot_subvolume_path.display()
+						"failed to move nested subvolume {} to {}",
+						live_path.display(),
+						target_path.display()
 					)
 				})?;
-			info!(
-				"snapshotting {} to {}",
-				restore_target_subvolume_path.display(),
-				subvolume_path.display()
-			);
-			let source = restore_target_subvolume_path.clone();
-			tokio::task::spawn_blocking(move || {
-				libbtrfsutil::create_snapshot(
-					&source,
-					&subvolume_path,
-					CreateSnapshotFlags::empty(),
-					None,
-				)
-			})
-			.await?
-			.with_context(|| {
-				format!(
-					"failed to snapshot subvolume '{}'",
-					restore_target_subvolume_path.display()
-				)
-			})?;
+			steps.push(RestoreStep::Moved(live_path, target_path));
 		}
+		Ok(())
+	}
 
-		let new_snapshot_metadata_path = self
-			.path()
-			.join(snapshot_path)
-			.join(new_snapshot.uuid.to_string())
-			.with_extension("snapshot.json");
+	/// Creates a writable snapshot of a subvolume's copy in
+	/// `restore_snapshot_dir` in its live location.
+	async fn restore_subvolume(&self, subvolume: &str, restore_snapshot_dir: &Path) -> Result<()> {
+		let restore_target_subvolume_path = restore_snapshot_dir.join(subvolume.replace('/', "__"));
+		let subvolume_path = self.path().join(subvolume);
 		info!(
-			"writing new snapshot metadata to {}",
-			new_snapshot_metadata_path.display()
+			"snapshotting {} to {}",
+			restore_target_subvolume_path.display(),
+			subvolume_path.display()
 		);
-		fs::write(
-			&new_snapshot_metadata_path,
-			serde_json::to_string_pretty(&new_snapshot)?,
-		)
-		.await
+		let source = restore_target_subvolume_path.clone();
+		tokio::task::spawn_blocking(move || {
+			libbtrfsutil::create_snapshot(
+				&source,
+				&subvolume_path,
+				CreateSnapshotFlags::empty(),
+				None,
+			)
+		})
+		.await?
 		.with_context(|| {
 			format!(
-				"failed to write snapshot metadata to {}",
-				new_snapshot_metadata_path.display()
+				"failed to snapshot subvolume '{}'",
+				restore_target_subvolume_path.display()
 			)
-		})?;
+		})
+	}
+}
 
-		Ok(new_snapshot)
+/// A change made to a filesystem while restoring a snapshot.
+enum RestoreStep {
+	/// A subvolume was moved from the first path to the second.
+	Moved(PathBuf, PathBuf),
+	/// A subvolume was snapshotted into place at this path.
+	Restored(PathBuf),
+}
+
+/// Undoes the changes made to a filesystem by a restore that failed,
+/// newest first, returning whether all of them were undone.
+///
+/// Any failures are logged, not returned,
+/// as we're already handling another error.
+async fn undo_restore_steps(steps: Vec<RestoreStep>) -> bool {
+	let mut undone = true;
+	for step in steps.into_iter().rev() {
+		let result = match step {
+			RestoreStep::Moved(from, to) => {
+				info!("Undoing restore: {} -> {}", to.display(), from.display());
+				fs::rename(&to, &from).await.with_context(|| {
+					format!("failed to rename {} to {}", to.display(), from.display())
+				})
+			}
+			RestoreStep::Restored(path) => {
+				info!("Undoing restore: deleting {}", path.display());
+				let display = path.display().to_string();
+				tokio::task::spawn_blocking(move || {
+					libbtrfsutil::delete_subvolume(&path, DeleteSubvolumeFlags::empty())
+				})
+				.await
+				.map_err(anyhow::Error::from)
+				.and_then(|result| {
+					result.with_context(|| format!("failed to delete subvolume {}", display))
+				})
+			}
+		};
+		if let Err(err) = result {
+			warn!("Failed to undo restore: {err:#}");
+			undone = false;
+		}
+	}
+	undone
+}
+
+/// Removes the empty directory that's left in place of a nested subvolume
+/// when its parent is snapshotted, so the subvolume can be put back there.
+async fn remove_placeholder(path: &Path) -> Result<()> {
+	if !path.is_dir() {
+		return Ok(());
 	}
+	fs::remove_dir(path).await.with_context(|| {
+		format!(
+			"failed to remove {}, which is in the way of a nested subvolume",
+			path.display()
+		)
+	})
 }
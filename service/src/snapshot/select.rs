@@ -0,0 +1,232 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::MountedBtrfs;
+use crate::{
+	config::Config,
+	mountinfo::{find_mount, read_mountinfo, MountInfo},
+	util::{check_subvolume_eligibility, SkipReason},
+};
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use std::{collections::HashSet, path::Path};
+use zbus_pop_snapshot::Error;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+	case_sensitive: true,
+	require_literal_separator: true,
+	require_literal_leading_dot: false,
+};
+
+/// A set of subvolumes, given as glob patterns of their paths (such as
+/// `@*/var/cache/**`), or as the absolute path they're mounted at
+/// (such as `/var/log`).
+///
+/// A subvolume matches if it, or a subvolume it's nested within, matches.
+#[derive(Debug, Default)]
+pub struct SubvolumeSpecs {
+	/// Each spec as it was given, along with the pattern it was resolved to.
+	specs: Vec<(String, Pattern)>,
+}
+
+impl SubvolumeSpecs {
+	/// Parses the given specs, looking up the subvolumes mounted at any
+	/// mountpoints in `mounts`.
+	///
+	/// Mountpoints must be on the root filesystem, as that's where the
+	/// subvolumes are looked for.
+	pub fn resolve(specs: &[String], mounts: &[MountInfo]) -> Result<Self> {
+		let root_source = find_mount(mounts, Path::new("/")).map(|root| &root.source);
+		let specs = specs
+			.iter()
+			.map(|spec| {
+				let pattern = if spec.starts_with('/') {
+					let mount = find_mount(mounts, Path::new(spec)).ok_or_else(|| {
+						Error::InvalidSubvolume(format!("nothing is mounted at {}", spec))
+					})?;
+					let subvolume = mount
+						.subvolume()
+						.filter(|_| mount.fs_type == "btrfs")
+						.ok_or_else(|| {
+							Error::InvalidSubvolume(format!("{} is not a btrfs subvolume", spec))
+						})?;
+					// Otherwise, a subvolume with the same name on the root
+					// filesystem would be snapshotted instead.
+					if root_source != Some(&mount.source) {
+						return Err(Error::InvalidSubvolume(format!(
+							"{} is not on the root filesystem",
+							spec
+						))
+						.into());
+					}
+					Pattern::new(&Pattern::escape(subvolume))
+				} else {
+					Pattern::new(spec)
+				}
+				.map_err(|err| {
+					Error::InvalidSubvolume(format!(
+						"invalid subvolume pattern '{}': {}",
+						spec, err
+					))
+				})?;
+				Ok((spec.clone(), pattern))
+			})
+			.collect::<Result<_>>()?;
+		Ok(Self { specs })
+	}
+
+	pub fn matches(&self, subvolume: &Path) -> bool {
+		subvolume.ancestors().any(|path| {
+			!path.as_os_str().is_empty()
+				&& self
+					.specs
+					.iter()
+					.any(|(_, pattern)| pattern.matches_path_with(path, MATCH_OPTIONS))
+		})
+	}
+
+	/// Finds the specs that don't match any of the given subvolumes.
+	pub fn unmatched<'a>(&'a self, subvolumes: &[String]) -> Vec<&'a str> {
+		self.specs
+			.iter()
+			.filter(|(_, pattern)| {
+				!subvolumes.iter().any(|subvolume| {
+					Path::new(subvolume)
+						.ancestors()
+						.any(|path| pattern.matches_path_with(path, MATCH_OPTIONS))
+				})
+			})
+			.map(|(spec, _)| spec.as_str())
+			.collect()
+	}
+}
+
+impl MountedBtrfs {
+	/// Works out which subvolumes a snapshot would capture.
+	///
+	/// `specs` are the subvolumes that were asked for, if any, otherwise the
+	/// configured included subvolumes are used. If neither is given, every
+	/// subvolume that isn't skipped by default is used. Nested subvolumes
+	/// are then filtered according to the configured policy.
+	pub async fn resolve_subvolumes(
+		&self,
+		specs: Option<Vec<String>>,
+		config: &Config,
+	) -> Result<Vec<String>> {
+		let mounts = read_mountinfo().await?;
+		let path = self.path().to_path_buf();
+		let subvolumes = match specs.or_else(|| config.include_subvolumes.clone()) {
+			Some(include) => {
+				let include = SubvolumeSpecs::resolve(&include, &mounts)?;
+				let all: Vec<String> = tokio::task::spawn_blocking(move || {
+					check_subvolume_eligibility(
+						&path,
+						&SubvolumeSpecs::default(),
+						&SubvolumeSpecs::default(),
+						&HashSet::new(),
+					)
+				})
+				.await?
+				.context("failed to list subvolumes")?
+				.into_iter()
+				.map(|(subvolume, _)| subvolume)
+				.collect();
+				let unmatched = include.unmatched(&all);
+				if !unmatched.is_empty() {
+					return Err(Error::InvalidSubvolume(format!(
+						"no subvolumes match {}",
+						unmatched.join(", ")
+					))
+					.into());
+				}
+				all.into_iter()
+					.filter(|subvolume| include.matches(Path::new(subvolume)))
+					.collect()
+			}
+			None => self
+				.check_subvolumes(config)
+				.await?
+				.into_iter()
+				.filter(|(_, reason)| reason.is_none())
+				.map(|(subvolume, _)| subvolume)
+				.collect(),
+		};
+		Ok(config.nested_subvolumes.filter(subvolumes))
+	}
+
+	/// Lists every subvolume that could be snapshotted, along with the reason
+	/// it's skipped by default, if it is.
+	pub async fn check_subvolumes(
+		&self,
+		config: &Config,
+	) -> Result<Vec<(String, Option<SkipReason>)>> {
+		let mounts = read_mountinfo().await?;
+		let path = self.path().to_path_buf();
+		let exclude = SubvolumeSpecs::resolve(&config.exclude_subvolumes, &mounts)
+			.context("failed to resolve excluded subvolumes")?;
+		let caches = SubvolumeSpecs::resolve(&config.cache_subvolumes, &mounts)
+			.context("failed to resolve cache subvolumes")?;
+		// Snapshots in the trash keep their clones until they're purged.
+		let mut snapshots = self
+			.read_snapshots(&config.snapshot_path)
+			.await
+			.context("failed to read snapshot metadata")?;
+		snapshots.extend(
+			self.list_trash(&config.trash_path)
+				.await
+				.context("failed to read trashed snapshot metadata")?,
+		);
+		let clones = snapshots
+			.into_iter()
+			.flat_map(|snapshot| snapshot.clones)
+			.flat_map(|clone| clone.subvolumes)
+			.collect::<HashSet<_>>();
+		tokio::task::spawn_blocking(move || {
+			check_subvolume_eligibility(&path, &exclude, &caches, &clones)
+		})
+		.await?
+		.context("failed to get eligible subvolumes to snapshot")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mountinfo::parse_mountinfo;
+
+	const CUSTOM: &str = include_str!("../../tests/fixtures/mountinfo/custom.mountinfo");
+
+	fn resolve(spec: &str) -> Result<SubvolumeSpecs> {
+		SubvolumeSpecs::resolve(&[spec.to_owned()], &parse_mountinfo(CUSTOM))
+	}
+
+	#[test]
+	fn resolves_mountpoints_to_subvolumes() {
+		let specs = resolve("/home").unwrap();
+		assert!(specs.matches(Path::new("users")));
+		assert!(specs.matches(Path::new("users/nested")));
+		assert!(!specs.matches(Path::new("system/rootfs")));
+	}
+
+	#[test]
+	fn rejects_mountpoints_on_other_filesystems() {
+		let err = resolve("/srv").unwrap_err();
+		assert!(
+			matches!(
+				err.downcast_ref::<Error>(),
+				Some(Error::InvalidSubvolume(_))
+			),
+			"{err:#}"
+		);
+	}
+
+	#[test]
+	fn rejects_mountpoints_that_arent_btrfs() {
+		let err = resolve("/dev").unwrap_err();
+		assert!(
+			matches!(
+				err.downcast_ref::<Error>(),
+				Some(Error::InvalidSubvolume(_))
+			),
+			"{err:#}"
+		);
+	}
+}
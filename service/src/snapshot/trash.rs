@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: MPL-2.0
+use super::{metadata::SnapshotMetadata, progress::ProgressReporter, MountedBtrfs};
+use anyhow::{anyhow, Context, Result};
+use libbtrfsutil::DeleteSubvolumeFlags;
+use std::{fs::File, mem::MaybeUninit, os::unix::io::AsRawFd, path::Path, time::Duration};
+use time::OffsetDateTime;
+use tokio::fs;
+use uuid::Uuid;
+use zbus_pop_snapshot::Error;
+
+impl MountedBtrfs {
+	/// Moves a snapshot into the trash, where it's kept until it's purged.
+	///
+	/// The snapshot's original metadata file is left for the caller to remove.
+	pub async fn trash_snapshot(
+		&self,
+		snapshot: &SnapshotMetadata,
+		snapshot_path: &Path,
+		trash_path: &Path,
+	) -> Result<()> {
+		let other_mounts = Self::mount_other_filesystems(snapshot).await?;
+		let trash_dir = self.path().join(trash_path);
+		fs::create_dir_all(&trash_dir)
+			.await
+			.with_context(|| format!("failed to create directory {}", trash_dir.display()))?;
+		let mut trashed = snapshot.clone();
+		trashed.deleted_time = Some(OffsetDateTime::now_utc());
+		// The metadata is written first, so the subvolumes are never
+		// in the trash without anything describing them.
+		trashed
+			.write(&trash_dir)
+			.await
+			.context("failed to write metadata to the trash")?;
+		info!("Moving snapshot {} to the trash", snapshot.uuid);
+		let mounts = std::iter::once(self)
+			.chain(&other_mounts)
+			.collect::<Vec<_>>();
+		if let Err(err) =
+			move_snapshot_dirs(&mounts, snapshot.uuid, snapshot_path, trash_path).await
+		{
+			if let Err(err) = fs::remove_file(trashed.path(&trash_dir)).await {
+				warn!("Failed to remove metadata from the trash: {err}");
+			}
+			return Err(err);
+		}
+		Ok(())
+	}
+
+	/// Lists the snapshots in the trash.
+	///
+	/// Unreadable metadata is logged and skipped.
+	pub async fn list_trash(&self, trash_path: &Path) -> Result<Vec<SnapshotMetadata>> {
+		self.read_snapshots(trash_path).await
+	}
+
+	/// Moves a snapshot out of the trash, back to where it was before
+	/// it was deleted.
+	pub async fn undelete_snapshot(
+		&self,
+		uuid: Uuid,
+		snapshot_path: &Path,
+		trash_path: &Path,
+	) -> Result<SnapshotMetadata> {
+		let trash_dir = self.path().join(trash_path);
+		let snapshots_dir = self.path().join(snapshot_path);
+		let mut snapshot = self
+			.list_trash(trash_path)
+			.await?
+			.into_iter()
+			.find(|snapshot| snapshot.uuid == uuid)
+			.ok_or_else(|| Error::NotFound(format!("snapshot {} is not in the trash", uuid)))?;
+		if !trash_dir.join(uuid.to_string()).is_dir() {
+			return Err(anyhow!(
+				"the subvolumes of snapshot {} are missing from the trash",
+				uuid
+			));
+		}
+		let other_mounts = Self::mount_other_filesystems(&snapshot).await?;
+		snapshot.deleted_time = None;
+		snapshot
+			.write(&snapshots_dir)
+			.await
+			.context("failed to write restored metadata")?;
+		info!("Moving snapshot {uuid} out of the trash");
+		let mounts = std::iter::once(self)
+			.chain(&other_mounts)
+			.collect::<Vec<_>>();
+		if let Err(err) = move_snapshot_dirs(&mounts, uuid, trash_path, snapshot_path).await {
+			if let Err(err) = fs::remove_file(snapshot.path(&snapshots_dir)).await {
+				warn!("Failed to remove restored metadata: {err}");
+			}
+			return Err(err);
+		}
+		fs::remove_file(snapshot.path(&trash_dir))
+			.await
+			.context("failed to remove metadata from the trash")?;
+		Ok(snapshot)
+	}
+
+	/// Permanently deletes a snapshot that's in the trash.
+	///
+	/// Fails if the snapshot still has clones, as nothing would keep track
+	/// of them once its metadata is gone.
+	pub async fn purge_snapshot(
+		&self,
+		snapshot: &SnapshotMetadata,
+		trash_path: &Path,
+	) -> Result<()> {
+		if !snapshot.clones.is_empty() {
+			return Err(anyhow!(
+				"snapshot {} still has {} clone(s)",
+				snapshot.uuid,
+				snapshot.clones.len()
+			));
+		}
+		let other_mounts = Self::mount_other_filesystems(snapshot).await?;
+		info!("Purging snapshot {} from the trash", snapshot.uuid);
+		for btrfs in std::iter::once(self).chain(&other_mounts) {
+			purge_snapshot_dir(
+				&btrfs
+					.path()
+					.join(trash_path)
+					.join(snapshot.uuid.to_string()),
+			)
+			.await?;
+		}
+		fs::remove_file(snapshot.path(&self.path().join(trash_path)))
+			.await
+			.context("failed to remove metadata from the trash")
+	}
+
+	/// Permanently deletes every snapshot in the trash,
+	/// except those that still have clones.
+	///
+	/// Stops before the next snapshot if the operation is cancelled.
+	///
+	/// Returns the UUIDs of the purged snapshots.
+	pub async fn purge_all_trash(
+		&self,
+		trash_path: &Path,
+		progress: &ProgressReporter,
+	) -> Result<Vec<Uuid>> {
+		let trash = self
+			.list_trash(trash_path)
+			.await?
+			.into_iter()
+			.filter(|snapshot| !keep_for_clones(snapshot))
+			.collect::<Vec<_>>();
+		progress.add_steps(trash.len());
+		let mut purged = Vec::with_capacity(trash.len());
+		for snapshot in trash {
+			progress.check_cancelled()?;
+			self.purge_snapshot(&snapshot, trash_path)
+				.await
+				.with_context(|| format!("failed to purge snapshot {}", snapshot.uuid))?;
+			purged.push(snapshot.uuid);
+			progress.complete_step();
+		}
+		Ok(purged)
+	}
+
+	/// Permanently deletes the snapshots that have been in the trash
+	/// for longer than the grace period, except those that still have clones.
+	///
+	/// If less than `min_free_space` percent of the filesystem is free, the
+	/// oldest remaining snapshot is purged as well. Only one is purged per
+	/// call, as btrfs frees the space of deleted subvolumes in the background,
+	/// so the effect won't be visible right away.
+	///
+	/// Stops before the next snapshot if the operation is cancelled.
+	///
+	/// Returns the UUIDs of the purged snapshots.
+	pub async fn purge_trash(
+		&self,
+		trash_path: &Path,
+		grace_period: Duration,
+		min_free_space: u8,
+		progress: &ProgressReporter,
+	) -> Result<Vec<Uuid>> {
+		let mut trash = self
+			.list_trash(trash_path)
+			.await?
+			.into_iter()
+			.filter(|snapshot| !keep_for_clones(snapshot))
+			.collect::<Vec<_>>();
+		// Snapshots without a deletion time sort first, as the oldest.
+		trash.sort_by_key(|snapshot| snapshot.deleted_time);
+		let now = OffsetDateTime::now_utc();
+		let mut purged = Vec::new();
+		let mut remaining = Vec::new();
+		for snapshot in trash {
+			let expired = snapshot
+				.deleted_time
+				.map_or(true, |deleted_time| deleted_time + grace_period <= now);
+			if !expired {
+				remaining.push(snapshot);
+				continue;
+			}
+			progress.check_cancelled()?;
+			match self.purge_snapshot(&snapshot, trash_path).await {
+				Ok(()) => purged.push(snapshot.uuid),
+				Err(err) => warn!("Failed to purge snapshot {}: {err:#}", snapshot.uuid),
+			}
+		}
+		if let Some(oldest) = remaining.first() {
+			let free_space = free_space_percent(self.path())?;
+			if free_space < f64::from(min_free_space) {
+				progress.check_cancelled()?;
+				info!(
+					"Only {free_space:.1}% of the filesystem is free, purging snapshot {} early",
+					oldest.uuid
+				);
+				self.purge_snapshot(oldest, trash_path)
+					.await
+					.with_context(|| format!("failed to purge snapshot {}", oldest.uuid))?;
+				purged.push(oldest.uuid);
+			}
+		}
+		Ok(purged)
+	}
+}
+
+/// Checks whether a trashed snapshot has to be kept because it still has
+/// clones, which are only ever deleted when asked to.
+fn keep_for_clones(snapshot: &SnapshotMetadata) -> bool {
+	if snapshot.clones.is_empty() {
+		return false;
+	}
+	info!(
+		"Keeping snapshot {} in the trash, as it still has {} clone(s)",
+		snapshot.uuid,
+		snapshot.clones.len()
+	);
+	true
+}
+
+/// Moves the directory of a snapshot's subvolumes from one path to another,
+/// on every given filesystem.
+///
+/// If moving fails on any of them, the ones that were already moved
+/// are moved back.
+async fn move_snapshot_dirs(
+	mounts: &[&MountedBtrfs],
+	uuid: Uuid,
+	from_path: &Path,
+	to_path: &Path,
+) -> Result<()> {
+	for (idx, btrfs) in mounts.iter().enumerate() {
+		let from = btrfs.path().join(from_path).join(uuid.to_string());
+		let to_dir = btrfs.path().join(to_path);
+		let result = match fs::create_dir_all(&to_dir).await {
+			Ok(()) => fs::rename(&from, to_dir.join(uuid.to_string())).await,
+			Err(err) => Err(err),
+		};
+		if let Err(err) = result {
+			for btrfs in &mounts[..idx] {
+				let moved = btrfs.path().join(to_path).join(uuid.to_string());
+				let original = btrfs.path().join(from_path).join(uuid.to_string());
+				if let Err(err) = fs::rename(&moved, &original).await {
+					warn!(
+						"Failed to move {} back to {}: {err}",
+						moved.display(),
+						original.display()
+					);
+				}
+			}
+			return Err(err).with_context(|| {
+				format!("failed to move {} to {}", from.display(), to_dir.display())
+			});
+		}
+	}
+	Ok(())
+}
+
+/// Deletes every subvolume in a snapshot's directory, then the directory itself.
+async fn purge_snapshot_dir(dir_path: &Path) -> Result<()> {
+	// The subvolumes may already be gone, if purging was interrupted.
+	if !dir_path.exists() {
+		return Ok(());
+	}
+	let mut dir = fs::read_dir(dir_path)
+		.await
+		.with_context(|| format!("failed to read directory {}", dir_path.display()))?;
+	while let Some(entry) = dir
+		.next_entry()
+		.await
+		.context("failed to read directory entry")?
+	{
+		let path = entry.path();
+		info!("deleting subvolume at {}", path.display());
+		// Backups can contain nested subvolumes, which stay with
+		// their replaced parent when they aren't carried over.
+		tokio::task::spawn_blocking(move || {
+			libbtrfsutil::delete_subvolume(&path, DeleteSubvolumeFlags::RECURSIVE)
+		})
+		.await?
+		.context("failed to delete subvolume")?;
+	}
+	fs::remove_dir_all(dir_path)
+		.await
+		.with_context(|| format!("failed to delete directory {}", dir_path.display()))
+}
+
+/// Gets the percentage of the filesystem at the given path that's available.
+fn free_space_percent(path: &Path) -> Result<f64> {
+	let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+	let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+	if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+		return Err(std::io::Error::last_os_error())
+			.with_context(|| format!("failed to get free space of {}", path.display()));
+	}
+	let stat = unsafe { stat.assume_init() };
+	if stat.f_blocks == 0 {
+		return Ok(100.0);
+	}
+	Ok(stat.f_bavail as f64 / stat.f_blocks as f64 * 100.0)
+}
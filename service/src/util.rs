@@ -1,61 +1,385 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use anyhow::{Context, Result};
+use crate::{
+	config::Config,
+	mountinfo::{find_mount, parse_mountinfo, read_mountinfo_file, unescape, MountInfo},
+	snapshot::{progress::Cancelled, select::SubvolumeSpecs},
+};
+use anyhow::{anyhow, Context, Result};
 use libbtrfsutil::{SubvolumeIterator, SubvolumeIteratorFlags};
-use std::path::{Path, PathBuf};
+use std::{
+	collections::HashSet,
+	os::unix::io::AsRawFd,
+	path::{Path, PathBuf},
+};
 use tokio::fs;
+use zbus::{fdo::DBusProxy, names::BusName, Connection, MessageHeader};
+use zbus_pop_snapshot::Error;
+
+/// The ID of the top-level subvolume of a btrfs filesystem.
+const TOP_LEVEL_SUBVOLUME_ID: u64 = 5;
+
+/// Finds the btrfs filesystem mounted at `/`.
+pub async fn find_root_mount() -> Result<MountInfo> {
+	root_mount(&read_mountinfo_file().await?)
+}
+
+/// Finds the btrfs filesystem mounted at `/`, in the contents of a mountinfo file.
+fn root_mount(mountinfo: &str) -> Result<MountInfo> {
+	let mounts = parse_mountinfo(mountinfo);
+	let root = find_mount(&mounts, Path::new("/"))
+		.context("nothing is mounted at /")?
+		.clone();
+	if root.fs_type != "btrfs" {
+		return Err(anyhow!("/ is on a {} filesystem, not btrfs", root.fs_type));
+	}
+	Ok(root)
+}
 
 /// Finds the btrfs partition that contains the root subvolume.
-///
-/// This works by scanning /proc/mounts for a mount that has the
-/// `subvol=/@root` option.
 pub async fn find_root_device() -> Result<PathBuf> {
-	let mounts = fs::read_to_string("/proc/mounts")
+	Ok(find_root_mount().await?.source)
+}
+
+/// Finds the UUID of the filesystem on the given device,
+/// by looking it up in `/dev/disk/by-uuid`.
+pub async fn filesystem_uuid(device: &Path) -> Result<String> {
+	let device = fs::canonicalize(device)
 		.await
-		.context("failed to read /proc/mounts")?;
-	mounts
-		.lines()
-		.find(|line| line.contains("subvol=/@root"))
-		.and_then(|line| line.split_whitespace().next())
-		.map(PathBuf::from)
-		.context("failed to find @root")
+		.with_context(|| format!("failed to resolve {}", device.display()))?;
+	let mut dir = fs::read_dir("/dev/disk/by-uuid")
+		.await
+		.context("failed to read /dev/disk/by-uuid")?;
+	while let Some(entry) = dir
+		.next_entry()
+		.await
+		.context("failed to read directory entry")?
+	{
+		if fs::canonicalize(entry.path()).await.ok().as_ref() == Some(&device) {
+			if let Some(uuid) = entry.file_name().to_str() {
+				return Ok(uuid.to_owned());
+			}
+		}
+	}
+	Err(anyhow!("no UUID found for {}", device.display()))
 }
 
-pub fn list_subvolumes_eligible_for_snapshotting(
+/// Finds the UUID of the root filesystem.
+pub async fn root_filesystem_uuid() -> Result<String> {
+	filesystem_uuid(&find_root_device().await?).await
+}
+
+/// Gets the name of the subvolume the system boots from, such as `@root`.
+///
+/// This is the configured root subvolume, if any, otherwise
+/// the subvolume mounted at `/`.
+pub async fn root_subvolume(config: &Config) -> Result<String> {
+	if let Some(root_subvolume) = &config.root_subvolume {
+		return Ok(root_subvolume.clone());
+	}
+	mounted_root_subvolume(&read_mountinfo_file().await?)
+}
+
+/// Gets the name of the subvolume mounted at `/`,
+/// from the contents of a mountinfo file.
+fn mounted_root_subvolume(mountinfo: &str) -> Result<String> {
+	let root = root_mount(mountinfo)?;
+	let subvolume = root
+		.subvolume()
+		.context("/ is not mounted from a subvolume")?;
+	// The top-level subvolume can't be moved aside to restore a snapshot.
+	if subvolume.is_empty() || root.subvolume_id() == Some(TOP_LEVEL_SUBVOLUME_ID) {
+		return Err(anyhow!(
+			"/ is mounted from the top-level subvolume, rather than a subvolume within it"
+		));
+	}
+	debug!(
+		"Found root subvolume '{}' (id {:?})",
+		subvolume,
+		root.subvolume_id()
+	);
+	Ok(subvolume.to_owned())
+}
+
+/// Why a subvolume isn't snapshotted by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+	/// It matches `exclude_subvolumes`.
+	Excluded,
+	/// It holds an active swapfile, which btrfs won't snapshot.
+	Swapfile,
+	/// It's marked NOCOW, as is usual for VM images and databases.
+	NoCow,
+	/// It matches `cache_subvolumes`.
+	Cache,
+	/// It's a writable clone of a snapshot.
+	Clone,
+}
+
+impl SkipReason {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Excluded => "excluded",
+			Self::Swapfile => "swapfile",
+			Self::NoCow => "nocow",
+			Self::Cache => "cache",
+			Self::Clone => "clone",
+		}
+	}
+}
+
+/// Lists the subvolumes that could be snapshotted, along with the reason
+/// each one is skipped by default, if it is.
+pub fn check_subvolume_eligibility(
 	root_path: &Path,
-	exclude_subvolumes: &[String],
-) -> Result<Vec<String>> {
+	exclude_subvolumes: &SubvolumeSpecs,
+	cache_subvolumes: &SubvolumeSpecs,
+	clone_subvolumes: &HashSet<String>,
+) -> Result<Vec<(String, Option<SkipReason>)>> {
 	let mut subvolumes = Vec::new();
 	let info =
 		libbtrfsutil::subvolume_info(root_path, None).context("failed to get subvolume info")?;
 	let iter = SubvolumeIterator::new(root_path, info.parent_id(), SubvolumeIteratorFlags::empty())
 		.context("failed to iterate root subvolumes")?;
 	let snapshots_path = PathBuf::from("@snapshots");
+	let swap_subvolumes = active_swapfile_subvolumes();
 	for subvolume in iter {
 		let (path, id) = subvolume.context("failed to get subvolume")?;
 		debug!("Found subvolume '{}' (id {id})", path.display());
-		if path.starts_with(&snapshots_path)
-			|| exclude_subvolumes
-				.iter()
-				.any(|exclude| path.starts_with(&exclude))
-		{
+		if path.starts_with(&snapshots_path) {
+			continue;
+		}
+		let full_path = root_path.join(&path);
+		let reason = if exclude_subvolumes.matches(&path) {
+			Some(SkipReason::Excluded)
+		} else if clone_subvolumes.contains(&*path.to_string_lossy()) {
+			Some(SkipReason::Clone)
+		} else if swap_subvolumes.contains(&id) {
+			Some(SkipReason::Swapfile)
+		} else if is_nocow(&full_path) {
+			Some(SkipReason::NoCow)
+		} else if cache_subvolumes.matches(&path) {
+			Some(SkipReason::Cache)
+		} else {
+			None
+		};
+		if let Some(reason) = reason {
 			debug!(
-				"Skipping subvolume '{}', it is not eligible for snapshotting",
-				path.display()
+				"Skipping subvolume '{}' by default ({})",
+				path.display(),
+				reason.as_str()
 			);
-			continue;
 		}
-		subvolumes.push(path.display().to_string());
+		subvolumes.push((path.display().to_string(), reason));
 	}
 	Ok(subvolumes)
 }
 
-pub trait ToFdoError<T> {
-	fn to_fdo_err(self) -> zbus::fdo::Result<T>;
+/// Finds the IDs of the subvolumes holding the swapfiles listed in
+/// `/proc/swaps`.
+fn active_swapfile_subvolumes() -> HashSet<u64> {
+	let swaps = match std::fs::read_to_string("/proc/swaps") {
+		Ok(swaps) => swaps,
+		Err(err) => {
+			warn!("Failed to read /proc/swaps: {err}");
+			return HashSet::new();
+		}
+	};
+	swaps
+		.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let mut fields = line.split_whitespace();
+			let path = unescape(fields.next()?);
+			if fields.next()? != "file" {
+				return None;
+			}
+			// Unlike `subvolume_info`, this works on any file within a subvolume.
+			match libbtrfsutil::subvolume_id(&path) {
+				Ok(id) => Some(id),
+				Err(err) => {
+					debug!("Swapfile {path} isn't on a btrfs subvolume: {err}");
+					None
+				}
+			}
+		})
+		.collect()
 }
 
-impl<T> ToFdoError<T> for anyhow::Result<T> {
-	fn to_fdo_err(self) -> zbus::fdo::Result<T> {
-		self.map_err(|err| zbus::fdo::Error::Failed(format!("{:?}", err)))
+/// Checks whether the top directory of a subvolume is marked NOCOW,
+/// which new files within it inherit.
+fn is_nocow(path: &Path) -> bool {
+	// The kernel declares this as taking a `long`, but every filesystem
+	// only reads and writes an `int`.
+	const FS_IOC_GETFLAGS: libc::c_ulong = ioctl_read(b'f', 1, std::mem::size_of::<libc::c_long>());
+	const FS_NOCOW_FL: libc::c_int = 0x00800000;
+	let file = match std::fs::File::open(path) {
+		Ok(file) => file,
+		Err(err) => {
+			warn!("Failed to open {}: {err}", path.display());
+			return false;
+		}
+	};
+	let mut flags: libc::c_int = 0;
+	if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS, &mut flags) } != 0 {
+		warn!(
+			"Failed to get the attributes of {}: {}",
+			path.display(),
+			std::io::Error::last_os_error()
+		);
+		return false;
+	}
+	flags & FS_NOCOW_FL != 0
+}
+
+/// Builds the request number of an ioctl that reads `size` bytes,
+/// like the kernel's `_IOR` macro.
+const fn ioctl_read(kind: u8, nr: u8, size: usize) -> libc::c_ulong {
+	#[cfg(any(
+		target_arch = "mips",
+		target_arch = "mips64",
+		target_arch = "powerpc",
+		target_arch = "powerpc64",
+		target_arch = "sparc64"
+	))]
+	const IOC_READ: libc::c_ulong = 2 << 29;
+	#[cfg(not(any(
+		target_arch = "mips",
+		target_arch = "mips64",
+		target_arch = "powerpc",
+		target_arch = "powerpc64",
+		target_arch = "sparc64"
+	)))]
+	const IOC_READ: libc::c_ulong = 2 << 30;
+	IOC_READ | (size as libc::c_ulong) << 16 | (kind as libc::c_ulong) << 8 | nr as libc::c_ulong
+}
+
+/// The process on the other end of a D-Bus method call.
+pub struct Caller {
+	pub uid: u32,
+	/// The name of the calling process, if it could be determined.
+	pub process: Option<String>,
+}
+
+/// Looks up the user and process that sent a D-Bus message.
+pub async fn get_caller(connection: &Connection, hdr: &MessageHeader<'_>) -> Result<Caller> {
+	let sender = hdr
+		.sender()
+		.context("failed to get message sender")?
+		.context("message has no sender")?
+		.clone();
+	let dbus = DBusProxy::new(connection)
+		.await
+		.context("failed to connect to D-Bus")?;
+	let uid = dbus
+		.get_connection_unix_user(BusName::from(sender.clone()))
+		.await
+		.context("failed to get sender's user ID")?;
+	let process = match dbus
+		.get_connection_unix_process_id(BusName::from(sender))
+		.await
+	{
+		Ok(pid) => fs::read_to_string(format!("/proc/{}/comm", pid))
+			.await
+			.map(|comm| comm.trim().to_owned())
+			.ok(),
+		Err(err) => {
+			warn!("Failed to get sender's process ID: {err}");
+			None
+		}
+	};
+	Ok(Caller { uid, process })
+}
+
+/// Fails unless the sender of a D-Bus message is root.
+///
+/// `action` describes what is being attempted, for the error message.
+pub async fn ensure_root(
+	connection: &Connection,
+	hdr: &MessageHeader<'_>,
+	action: &str,
+) -> Result<()> {
+	let caller = get_caller(connection, hdr)
+		.await
+		.context("failed to identify the caller")?;
+	if caller.uid != 0 {
+		return Err(Error::PermissionDenied(format!("only root can {}", action)).into());
+	}
+	Ok(())
+}
+
+pub trait ToDBusError<T> {
+	fn to_dbus_err(self) -> zbus_pop_snapshot::Result<T>;
+}
+
+impl<T> ToDBusError<T> for anyhow::Result<T> {
+	fn to_dbus_err(self) -> zbus_pop_snapshot::Result<T> {
+		self.map_err(|err| dbus_error(&err))
+	}
+}
+
+/// Picks the D-Bus error that best describes `err`, from the first error
+/// in its chain that says what kind of error it is.
+pub fn dbus_error(err: &anyhow::Error) -> Error {
+	let message = format!("{:#}", err);
+	for cause in err.chain() {
+		if let Some(err) = cause.downcast_ref::<Error>() {
+			return err.with_message(message);
+		}
+		if cause.is::<Cancelled>() {
+			return Error::Cancelled(message);
+		}
+		if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+			match err.raw_os_error() {
+				Some(libc::ENOSPC | libc::EDQUOT) => return Error::NoSpace(message),
+				Some(libc::EACCES | libc::EPERM) => return Error::PermissionDenied(message),
+				_ => {}
+			}
+		}
+	}
+	Error::Failed(message)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const POP: &str = include_str!("../tests/fixtures/mountinfo/btrfs.mountinfo");
+	const UBUNTU: &str = include_str!("../tests/fixtures/mountinfo/ubuntu.mountinfo");
+	const FEDORA: &str = include_str!("../tests/fixtures/mountinfo/fedora.mountinfo");
+	const CUSTOM: &str = include_str!("../tests/fixtures/mountinfo/custom.mountinfo");
+	const TOP_LEVEL: &str = include_str!("../tests/fixtures/mountinfo/toplevel.mountinfo");
+
+	#[test]
+	fn detects_the_root_subvolume_of_common_layouts() {
+		for (mountinfo, subvolume, source) in [
+			(POP, "@root", "/dev/nvme0n1p3"),
+			(UBUNTU, "@", "/dev/sda2"),
+			(FEDORA, "root", "/dev/vda3"),
+			(CUSTOM, "system/rootfs", "/dev/mapper/luks-root"),
+		] {
+			assert_eq!(mounted_root_subvolume(mountinfo).unwrap(), subvolume);
+			assert_eq!(root_mount(mountinfo).unwrap().source, Path::new(source));
+		}
+	}
+
+	#[test]
+	fn rejects_a_root_that_isnt_a_subvolume() {
+		let err = mounted_root_subvolume(TOP_LEVEL).unwrap_err();
+		assert!(err.to_string().contains("top-level subvolume"), "{err}");
+	}
+
+	#[test]
+	fn rejects_a_root_that_isnt_btrfs() {
+		let mountinfo = "21 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw,errors=remount-ro\n";
+		let err = root_mount(mountinfo).unwrap_err();
+		assert!(err.to_string().contains("ext4"), "{err}");
+	}
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn builds_ioctl_request_numbers() {
+		// `FS_IOC_GETFLAGS`, as found in `linux/fs.h`.
+		assert_eq!(ioctl_read(b'f', 1, 8), 0x80086601);
 	}
 }